@@ -145,7 +145,7 @@ void get_frame_from_controller(struct LeapRustController* lrcontroller, struct L
     const Leap::HandList hands = leapFrame.hands();
     int hands_recorded = 0;
 
-    for (Leap::HandList::const_iterator hl = hands.begin(); hl != hands.end(); ++hl, ++hands_recorded) {
+    for (Leap::HandList::const_iterator hl = hands.begin(); hl != hands.end() && hands_recorded < 2; ++hl, ++hands_recorded) {
         const Leap::Hand hand = *hl;
         LeapRustHand *lrHand = &(rustFrame->hands[hands_recorded]);
         lrHand->fingerCount = hand.fingers().count() > 5 ? 5 : hand.fingers().count();
@@ -183,11 +183,12 @@ void get_frame_from_controller(struct LeapRustController* lrcontroller, struct L
         int fingers_recorded = 0;
         Leap::FingerList fingers = hand.fingers();
 
-        for (Leap::FingerList::const_iterator fl = fingers.begin(); fl != fingers.end(), fingers_recorded < 5; ++fl, ++fingers_recorded) {
+        for (Leap::FingerList::const_iterator fl = fingers.begin(); fl != fingers.end() && fingers_recorded < 5; ++fl, ++fingers_recorded) {
             const Leap::Finger finger = *fl;
             LeapRustFinger *lrFinger = &(lrHand->fingers[fingers_recorded]);
 
             /* record finger stuff */
+            lrFinger->type = (LeapRustFingerType)finger.type();
             lrFinger->tipPosition = convert_vector(finger.tipPosition());
             lrFinger->tipVelocity = convert_vector(finger.tipVelocity());
             lrFinger->id = finger.id();
@@ -0,0 +1,246 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::control::ControlSource;
+use crate::preset::Preset;
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum ArpPattern {
+    // fingers play their notes directly
+    Off,
+    Up,
+    Down,
+    UpDown,
+    Random,
+    // every held note together on each step
+    Chord,
+}
+
+impl ArpPattern {
+    pub const ALL: [ArpPattern; 6] = [
+        ArpPattern::Off,
+        ArpPattern::Up,
+        ArpPattern::Down,
+        ArpPattern::UpDown,
+        ArpPattern::Random,
+        ArpPattern::Chord,
+    ];
+
+    pub fn next(&self) -> ArpPattern {
+        let index = ArpPattern::ALL.iter().position(|x| x == self).unwrap_or(0);
+        ArpPattern::ALL[(index + 1) % ArpPattern::ALL.len()]
+    }
+}
+
+impl FromStr for ArpPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ArpPattern, String> {
+        ArpPattern::ALL.iter()
+            .find(|x| x.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown arpeggiator pattern {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArpSettings {
+    pub pattern: ArpPattern,
+    // steps per beat, 4 plays sixteenths
+    pub division: u32,
+    // fraction of each step the note sounds for
+    pub gate: f32,
+    // speeds the steps up from half to four times the rate, Off keeps it
+    pub rate_source: ControlSource,
+    // replaces the gate, Off keeps it
+    pub gate_source: ControlSource,
+}
+
+impl ArpSettings {
+    pub fn new() -> ArpSettings {
+        ArpSettings {
+            pattern: ArpPattern::Off,
+            division: 4,
+            gate: 0.5,
+            rate_source: ControlSource::PalmY,
+            gate_source: ControlSource::Pinch,
+        }
+    }
+
+    pub fn next_division(&self) -> u32 {
+        self.division % 4 + 1
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} 1/{}", self.pattern, self.division * 4)
+    }
+
+    pub fn from_preset(preset: &Preset) -> ArpSettings {
+        let defaults = ArpSettings::new();
+        ArpSettings {
+            pattern: preset.get("arp.pattern").unwrap_or(defaults.pattern),
+            division: preset.get("arp.division").unwrap_or(defaults.division).max(1),
+            gate: preset.get("arp.gate").unwrap_or(defaults.gate),
+            rate_source: preset.get("arp.rate_source").unwrap_or(defaults.rate_source),
+            gate_source: preset.get("arp.gate_source").unwrap_or(defaults.gate_source),
+        }
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset) {
+        preset.set("arp.pattern", self.pattern);
+        preset.set("arp.division", self.division);
+        preset.set("arp.gate", self.gate);
+        preset.set("arp.rate_source", self.rate_source);
+        preset.set("arp.gate_source", self.gate_source);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArpEvent<K> {
+    // the notes sounding from the last step end
+    Stop,
+    // these start, after stopping whatever the last step played
+    Start(Vec<K>),
+}
+
+// Steps through the held notes, keyed by whatever the caller uses to find
+// them again. Counts samples so the steps land exactly on time.
+#[derive(Debug, Clone)]
+pub struct Arpeggiator<K> {
+    pub settings: ArpSettings,
+    // held notes and their pitch, lowest first
+    held: Vec<(K, f32)>,
+    step: usize,
+    rising: bool,
+    samples_to_step: f32,
+    samples_to_stop: Option<f32>,
+    rate_scale: f32,
+    gate: f32,
+    seed: u32,
+    // following the transport, so the rate only moves in doublings
+    pub synced: bool,
+}
+
+impl<K: Copy + PartialEq> Arpeggiator<K> {
+    pub fn new(settings: ArpSettings) -> Arpeggiator<K> {
+        Arpeggiator {
+            settings: settings,
+            held: Vec::new(),
+            step: 0,
+            rising: true,
+            samples_to_step: 0.0,
+            samples_to_stop: None,
+            rate_scale: 1.0,
+            gate: settings.gate,
+            seed: 0x9e37_79b9,
+            synced: false,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.settings.pattern != ArpPattern::Off
+    }
+
+    pub fn hold(&mut self, key: K, pitch: f32) {
+        if self.held.iter().any(|x| x.0 == key) {
+            return;
+        }
+        // the first note plays straight away
+        if self.held.is_empty() {
+            self.samples_to_step = 0.0;
+            self.step = 0;
+            self.rising = true;
+        }
+        let index = self.held.iter().position(|x| x.1 > pitch).unwrap_or(self.held.len());
+        self.held.insert(index, (key, pitch));
+    }
+
+    pub fn release(&mut self, key: K) {
+        self.held.retain(|x| x.0 != key);
+    }
+
+    pub fn clear(&mut self) {
+        self.held.clear();
+    }
+
+    // Hand readings, 0..1, for the rate and gate sources
+    pub fn set_controls(&mut self, rate: Option<f32>, gate: Option<f32>) {
+        let octaves = rate.map_or(0.0, |x| x * 3.0 - 1.0);
+        self.rate_scale = 2f32.powf(if self.synced { octaves.round() } else { octaves });
+        self.gate = gate.unwrap_or(self.settings.gate).max(0.05).min(1.0);
+    }
+
+    fn step_length(&self, sample_rate: u32, bpm: f32) -> f32 {
+        let steps_per_minute = bpm * self.settings.division as f32 * self.rate_scale;
+        sample_rate as f32 * 60.0 / steps_per_minute.max(1.0)
+    }
+
+    // Lines the steps up with a beat of the transport. Slower than a step a
+    // beat, steps span several beats and are left alone.
+    pub fn align_to_beat(&mut self, sample_rate: u32, bpm: f32) {
+        if (self.settings.division as f32 * self.rate_scale) < 1.0 {
+            return;
+        }
+        // a step due soon plays now, one that just played counts as on the beat
+        let length = self.step_length(sample_rate, bpm);
+        self.samples_to_step = if self.samples_to_step < length / 2.0 { 0.0 } else { length };
+    }
+
+    fn next_random(&mut self) -> u32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed
+    }
+
+    fn next_notes(&mut self) -> Vec<K> {
+        let count = self.held.len();
+        let index = match self.settings.pattern {
+            ArpPattern::Off => return Vec::new(),
+            ArpPattern::Chord => return self.held.iter().map(|x| x.0).collect(),
+            ArpPattern::Up => self.step % count,
+            ArpPattern::Down => count - 1 - self.step % count,
+            ArpPattern::Random => self.next_random() as usize % count,
+            ArpPattern::UpDown => {
+                // turn round at the ends without playing them twice
+                let index = self.step.min(count - 1);
+                if count > 1 {
+                    if self.rising && index + 1 >= count {
+                        self.rising = false;
+                    } else if !self.rising && index == 0 {
+                        self.rising = true;
+                    }
+                }
+                self.step = if self.rising { index + 1 } else { index.saturating_sub(1) };
+                return vec!(self.held[index].0);
+            },
+        };
+        self.step = self.step.wrapping_add(1);
+        vec!(self.held[index].0)
+    }
+
+    // Call once per sample with the transport's tempo
+    pub fn tick(&mut self, sample_rate: u32, bpm: f32) -> Option<ArpEvent<K>> {
+        if !self.is_active() {
+            return None;
+        }
+        if let Some(samples) = &mut self.samples_to_stop {
+            *samples -= 1.0;
+            if *samples <= 0.0 {
+                self.samples_to_stop = None;
+                return Some(ArpEvent::Stop);
+            }
+        }
+        self.samples_to_step -= 1.0;
+        if self.samples_to_step > 0.0 {
+            return None;
+        }
+        let length = self.step_length(sample_rate, bpm);
+        self.samples_to_step += length;
+        if self.held.is_empty() {
+            return None;
+        }
+        self.samples_to_stop = Some(length * self.gate);
+        Some(ArpEvent::Start(self.next_notes()))
+    }
+}
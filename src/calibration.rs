@@ -0,0 +1,362 @@
+use std::str::FromStr;
+
+use crate::handview::{Finger, HandRole, HandSettings, HandView, Side};
+use crate::leaprust::{LeapRustFinger, LeapRustFrame};
+use crate::preset::Preset;
+use crate::trigger::{finger_flexion, DetectionMode, TriggerSettings};
+
+pub const PROFILE_PATH: &str = "leapsynth.profile";
+
+const FINGER_NAMES: [&str; 5] = ["thumb", "index", "middle", "ring", "little"];
+// Where between the pressed and resting positions a finger triggers and lets go
+const PRESS_FRACTION: f32 = 0.4;
+const RELEASE_FRACTION: f32 = 0.6;
+
+// Fingertip heights in millimetres and curl in radians with the hand
+// relaxed and with the finger pressed all the way down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FingerCalibration {
+    pub rest: f32,
+    pub press: f32,
+    pub rest_flexion: f32,
+    pub press_flexion: f32,
+}
+
+impl FingerCalibration {
+    fn height(&self, fraction: f32) -> f32 {
+        self.press + (self.rest - self.press) * fraction
+    }
+
+    fn flexion(&self, fraction: f32) -> f32 {
+        self.press_flexion + (self.rest_flexion - self.press_flexion) * fraction
+    }
+}
+
+// One user's reach: per finger trigger heights and the box, in millimetres,
+// their fingertips move around in, plus how long a press holds and how
+// fast a finger has to come down to press
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub detection: DetectionMode,
+    pub fingers: [FingerCalibration; 5],
+    pub x: (f32, f32),
+    pub y: (f32, f32),
+    pub z: (f32, f32),
+    // microseconds
+    pub min_hold: i32,
+    // mm/s
+    pub min_speed: f32,
+}
+
+impl Calibration {
+    pub fn new() -> Calibration {
+        let finger = FingerCalibration { rest: 230.0, press: 180.0, rest_flexion: 0.4, press_flexion: 1.8 };
+        let mut fingers = [finger; 5];
+        fingers[0].rest = 220.0;
+        fingers[0].press = 170.0;
+        Calibration {
+            detection: DetectionMode::Height,
+            fingers: fingers,
+            x: (-420.0, 330.0),
+            y: (10.0, 650.0),
+            z: (-150.0, 150.0),
+            min_hold: 50_000,
+            min_speed: 0.0,
+        }
+    }
+
+    pub fn press_height(&self, finger: usize) -> f32 {
+        self.fingers[finger].height(PRESS_FRACTION)
+    }
+
+    pub fn release_height(&self, finger: usize) -> f32 {
+        self.fingers[finger].height(RELEASE_FRACTION)
+    }
+
+    pub fn trigger_settings(&self, finger: usize) -> TriggerSettings {
+        let settings = TriggerSettings::new(self.press_height(finger), self.release_height(finger))
+            .with_min_hold(self.min_hold)
+            .with_min_speed(self.min_speed);
+        match self.detection {
+            DetectionMode::Height => settings,
+            DetectionMode::Flexion => {
+                let calibration = &self.fingers[finger];
+                settings.with_flexion(calibration.flexion(PRESS_FRACTION), calibration.flexion(RELEASE_FRACTION))
+            },
+        }
+    }
+
+    // Whether the finger is down right now, without any hysteresis
+    pub fn is_pressed(&self, finger: usize, leap_finger: &LeapRustFinger) -> bool {
+        self.trigger_settings(finger).is_pressed(leap_finger)
+    }
+
+    // Keys are calibration.<side>.<setting> and trigger.<side>.<setting>,
+    // falling back to the same key without the side, which both hands
+    // shared before they were calibrated apart
+    pub fn from_preset(preset: &Preset, side: Side) -> Calibration {
+        let mut calibration = Calibration::new();
+        calibration.detection = side_get(preset, "calibration", side, "detection").unwrap_or(calibration.detection);
+        for (finger, name) in FINGER_NAMES.iter().enumerate() {
+            let settings = &mut calibration.fingers[finger];
+            settings.rest = side_get(preset, "calibration", side, &format!("{}.rest", name)).unwrap_or(settings.rest);
+            settings.press = side_get(preset, "calibration", side, &format!("{}.press", name)).unwrap_or(settings.press);
+            settings.rest_flexion = side_get(preset, "calibration", side, &format!("{}.rest_flexion", name)).unwrap_or(settings.rest_flexion);
+            settings.press_flexion = side_get(preset, "calibration", side, &format!("{}.press_flexion", name)).unwrap_or(settings.press_flexion);
+        }
+        for (axis, range) in [("x", &mut calibration.x), ("y", &mut calibration.y), ("z", &mut calibration.z)] {
+            range.0 = side_get(preset, "calibration", side, &format!("{}.min", axis)).unwrap_or(range.0);
+            range.1 = side_get(preset, "calibration", side, &format!("{}.max", axis)).unwrap_or(range.1);
+        }
+        calibration.min_hold = side_get(preset, "trigger", side, "min_hold").unwrap_or(calibration.min_hold).max(0);
+        calibration.min_speed = side_get(preset, "trigger", side, "min_speed").unwrap_or(calibration.min_speed).max(0.0);
+        calibration
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset, side: Side) {
+        let key = |group: &str, name: &str| format!("{}.{}.{}", group, side_name(side), name);
+        preset.set(&key("calibration", "detection"), self.detection);
+        for (finger, name) in FINGER_NAMES.iter().enumerate() {
+            preset.set(&key("calibration", &format!("{}.rest", name)), self.fingers[finger].rest);
+            preset.set(&key("calibration", &format!("{}.press", name)), self.fingers[finger].press);
+            preset.set(&key("calibration", &format!("{}.rest_flexion", name)), self.fingers[finger].rest_flexion);
+            preset.set(&key("calibration", &format!("{}.press_flexion", name)), self.fingers[finger].press_flexion);
+        }
+        for (axis, range) in [("x", self.x), ("y", self.y), ("z", self.z)] {
+            preset.set(&key("calibration", &format!("{}.min", axis)), range.0);
+            preset.set(&key("calibration", &format!("{}.max", axis)), range.1);
+        }
+        preset.set(&key("trigger", "min_hold"), self.min_hold);
+        preset.set(&key("trigger", "min_speed"), self.min_speed);
+    }
+}
+
+fn side_name(side: Side) -> String {
+    side.to_string().to_lowercase()
+}
+
+fn side_get<T: FromStr>(preset: &Preset, group: &str, side: Side, name: &str) -> Option<T> {
+    preset.get(&format!("{}.{}.{}", group, side_name(side), name))
+        .or_else(|| preset.get(&format!("{}.{}", group, name)))
+}
+
+// Each hand's calibration, as the two rarely reach the same
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HandCalibrations {
+    pub left: Calibration,
+    pub right: Calibration,
+}
+
+impl HandCalibrations {
+    pub fn hand(&self, side: Side) -> &Calibration {
+        match side {
+            Side::Left => &self.left,
+            Side::Right => &self.right,
+        }
+    }
+
+    pub fn hand_mut(&mut self, side: Side) -> &mut Calibration {
+        match side {
+            Side::Left => &mut self.left,
+            Side::Right => &mut self.right,
+        }
+    }
+
+    pub fn load(path: &str) -> HandCalibrations {
+        HandCalibrations::from_preset(&Preset::load(path))
+    }
+
+    pub fn save(&self, path: &str) {
+        let mut profile = Preset::new();
+        self.store_preset(&mut profile);
+        match profile.save(path) {
+            Ok(()) => println!("saved calibration to {}", path),
+            Err(err) => eprintln!("couldn't save calibration to {}: {}", path, err),
+        }
+    }
+
+    pub fn from_preset(preset: &Preset) -> HandCalibrations {
+        HandCalibrations {
+            left: Calibration::from_preset(preset, Side::Left),
+            right: Calibration::from_preset(preset, Side::Right),
+        }
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset) {
+        self.left.store_preset(preset, Side::Left);
+        self.right.store_preset(preset, Side::Right);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationStep {
+    Off,
+    // hand relaxed above the controller
+    Rest,
+    // one finger pressed down at a time
+    Press(usize),
+    // whole hand swept around the space it plays in
+    Workspace,
+}
+
+impl CalibrationStep {
+    fn next(&self) -> CalibrationStep {
+        match self {
+            CalibrationStep::Off => CalibrationStep::Rest,
+            CalibrationStep::Rest => CalibrationStep::Press(0),
+            CalibrationStep::Press(finger) if *finger + 1 < FINGER_NAMES.len() => CalibrationStep::Press(finger + 1),
+            CalibrationStep::Press(_) => CalibrationStep::Workspace,
+            CalibrationStep::Workspace => CalibrationStep::Off,
+        }
+    }
+
+    pub fn prompt(&self, side: Side) -> String {
+        let side = side_name(side);
+        match self {
+            CalibrationStep::Off => String::from("Not calibrating"),
+            CalibrationStep::Rest => format!("Hold your {} hand relaxed over the controller", side),
+            CalibrationStep::Press(finger) => format!("Press your {} {} down as if playing a key", side, FINGER_NAMES[*finger]),
+            CalibrationStep::Workspace => format!("Move your {} hand around everywhere you want to play", side),
+        }
+    }
+}
+
+// Collects readings for each step of the calibration wizard, one playing
+// hand after the other
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibrator {
+    pub step: CalibrationStep,
+    pub side: Side,
+    // the other hand, when it plays too and still has to be calibrated
+    next_side: Option<Side>,
+    calibrations: HandCalibrations,
+    rest_sum: [f32; 5],
+    rest_flexion_sum: [f32; 5],
+    rest_count: u32,
+    press_min: f32,
+    press_flexion_max: f32,
+    bounds: [(f32, f32); 3],
+}
+
+impl Calibrator {
+    pub fn new(calibrations: HandCalibrations) -> Calibrator {
+        Calibrator {
+            step: CalibrationStep::Off,
+            side: Side::Right,
+            next_side: None,
+            calibrations: calibrations,
+            rest_sum: [0.0; 5],
+            rest_flexion_sum: [0.0; 5],
+            rest_count: 0,
+            press_min: f32::INFINITY,
+            press_flexion_max: 0.0,
+            bounds: [(f32::INFINITY, f32::NEG_INFINITY); 3],
+        }
+    }
+
+    // Calibrates the playing hands, right first, or the right hand when
+    // neither plays
+    pub fn start(&mut self, calibrations: HandCalibrations, hands: &HandSettings) {
+        let playing: Vec<Side> = [Side::Right, Side::Left].into_iter()
+            .filter(|x| hands.role(*x) == HandRole::Play)
+            .collect();
+        self.start_side(calibrations, playing.first().copied().unwrap_or(Side::Right));
+        self.next_side = playing.get(1).copied();
+    }
+
+    fn start_side(&mut self, calibrations: HandCalibrations, side: Side) {
+        *self = Calibrator::new(calibrations);
+        self.side = side;
+        self.step = CalibrationStep::Rest;
+    }
+
+    pub fn prompt(&self) -> String {
+        self.step.prompt(self.side)
+    }
+
+    pub fn record(&mut self, frame: &LeapRustFrame) {
+        let hands = HandView::new(frame);
+        let hand = match hands.hand(self.side) {
+            Some(hand) => hand,
+            None => return,
+        };
+        match self.step {
+            CalibrationStep::Off => {},
+            CalibrationStep::Rest => {
+                let fingers: Vec<&LeapRustFinger> = Finger::ALL.iter()
+                    .filter_map(|x| hand.finger(x.leap_type()))
+                    .collect();
+                if fingers.len() < Finger::ALL.len() {
+                    return;
+                }
+                for (finger, leap_finger) in fingers.into_iter().enumerate() {
+                    self.rest_sum[finger] += leap_finger.tipPosition.y;
+                    self.rest_flexion_sum[finger] += finger_flexion(leap_finger);
+                }
+                self.rest_count += 1;
+            },
+            CalibrationStep::Press(finger) => {
+                if let Some(leap_finger) = hand.finger(Finger::ALL[finger].leap_type()) {
+                    self.press_min = self.press_min.min(leap_finger.tipPosition.y);
+                    self.press_flexion_max = self.press_flexion_max.max(finger_flexion(leap_finger));
+                }
+            },
+            CalibrationStep::Workspace => {
+                for finger in &hand.fingers[..(hand.fingerCount.max(0) as usize).min(5)] {
+                    let tip = finger.tipPosition;
+                    for (bound, value) in self.bounds.iter_mut().zip([tip.x, tip.y, tip.z]) {
+                        *bound = (bound.0.min(value), bound.1.max(value));
+                    }
+                }
+            },
+        }
+    }
+
+    // Keeps what the current step measured and moves on, returning the
+    // calibrations once every step is done for every hand. Steps without
+    // readings keep the previous values.
+    pub fn advance(&mut self) -> Option<HandCalibrations> {
+        let calibration = self.calibrations.hand_mut(self.side);
+        match self.step {
+            CalibrationStep::Off => return None,
+            CalibrationStep::Rest => {
+                if self.rest_count > 0 {
+                    for finger in 0..5 {
+                        calibration.fingers[finger].rest = self.rest_sum[finger] / self.rest_count as f32;
+                        calibration.fingers[finger].rest_flexion = self.rest_flexion_sum[finger] / self.rest_count as f32;
+                    }
+                }
+            },
+            CalibrationStep::Press(finger) => {
+                let settings = &mut calibration.fingers[finger];
+                if self.press_min < settings.rest {
+                    settings.press = self.press_min;
+                }
+                if self.press_flexion_max > settings.rest_flexion {
+                    settings.press_flexion = self.press_flexion_max;
+                }
+                self.press_min = f32::INFINITY;
+                self.press_flexion_max = 0.0;
+            },
+            CalibrationStep::Workspace => {
+                let ranges = [&mut calibration.x, &mut calibration.y, &mut calibration.z];
+                for (range, bound) in ranges.into_iter().zip(self.bounds) {
+                    if bound.0 < bound.1 {
+                        *range = bound;
+                    }
+                }
+            },
+        }
+        self.step = self.step.next();
+        if self.step != CalibrationStep::Off {
+            return None;
+        }
+        match self.next_side {
+            Some(next_side) => {
+                self.start_side(self.calibrations, next_side);
+                None
+            },
+            None => Some(self.calibrations),
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::notefreq;
+use crate::preset::Preset;
+
+const KEY_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum Scale {
+    Major,
+    Minor,
+    HarmonicMinor,
+    Dorian,
+    Mixolydian,
+}
+
+impl Scale {
+    pub const ALL: [Scale; 5] = [Scale::Major, Scale::Minor, Scale::HarmonicMinor, Scale::Dorian, Scale::Mixolydian];
+
+    pub fn next(&self) -> Scale {
+        let index = Scale::ALL.iter().position(|x| x == self).unwrap_or(0);
+        Scale::ALL[(index + 1) % Scale::ALL.len()]
+    }
+
+    // Semitones above the key of each degree
+    fn steps(&self) -> [i32; 7] {
+        match self {
+            Scale::Major => [0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => [0, 2, 3, 5, 7, 8, 10],
+            Scale::HarmonicMinor => [0, 2, 3, 5, 7, 8, 11],
+            Scale::Dorian => [0, 2, 3, 5, 7, 9, 10],
+            Scale::Mixolydian => [0, 2, 4, 5, 7, 9, 10],
+        }
+    }
+
+    // Semitones above the key of any degree, counting on into higher octaves
+    fn semitones(&self, degree: usize) -> i32 {
+        self.steps()[degree % 7] + 12 * (degree / 7) as i32
+    }
+}
+
+// Scale degrees stacked on the chord's root, so the quality of each chord
+// follows the scale
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum ChordQuality {
+    Triad,
+    Seventh,
+    Sus2,
+    Sus4,
+    // root and fifth
+    Power,
+}
+
+impl ChordQuality {
+    pub const ALL: [ChordQuality; 5] = [
+        ChordQuality::Triad,
+        ChordQuality::Seventh,
+        ChordQuality::Sus2,
+        ChordQuality::Sus4,
+        ChordQuality::Power,
+    ];
+
+    pub fn next(&self) -> ChordQuality {
+        let index = ChordQuality::ALL.iter().position(|x| x == self).unwrap_or(0);
+        ChordQuality::ALL[(index + 1) % ChordQuality::ALL.len()]
+    }
+
+    fn degrees(&self) -> &'static [usize] {
+        match self {
+            ChordQuality::Triad => &[0, 2, 4],
+            ChordQuality::Seventh => &[0, 2, 4, 6],
+            ChordQuality::Sus2 => &[0, 1, 4],
+            ChordQuality::Sus4 => &[0, 3, 4],
+            ChordQuality::Power => &[0, 4],
+        }
+    }
+
+    // The same chord with its seventh added
+    fn with_seventh(&self) -> ChordQuality {
+        match self {
+            ChordQuality::Triad => ChordQuality::Seventh,
+            quality => *quality,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum Voicing {
+    // every note within an octave
+    Close,
+    // second note up an octave
+    Open,
+    // root down an octave under the rest
+    Spread,
+}
+
+impl Voicing {
+    pub const ALL: [Voicing; 3] = [Voicing::Close, Voicing::Open, Voicing::Spread];
+
+    pub fn next(&self) -> Voicing {
+        let index = Voicing::ALL.iter().position(|x| x == self).unwrap_or(0);
+        Voicing::ALL[(index + 1) % Voicing::ALL.len()]
+    }
+}
+
+impl FromStr for Scale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Scale, String> {
+        Scale::ALL.iter()
+            .find(|x| x.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown scale {}", s))
+    }
+}
+
+impl FromStr for ChordQuality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ChordQuality, String> {
+        ChordQuality::ALL.iter()
+            .find(|x| x.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown chord quality {}", s))
+    }
+}
+
+impl FromStr for Voicing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Voicing, String> {
+        Voicing::ALL.iter()
+            .find(|x| x.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown voicing {}", s))
+    }
+}
+
+// Builds a chord on each scale degree for single finger chord playing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChordSettings {
+    // pitch class of the key, 0 is C
+    pub key: i32,
+    // octave of the key's root on the first degree
+    pub octave: i32,
+    pub scale: Scale,
+    pub quality: ChordQuality,
+    pub voicing: Voicing,
+    // how many of the lowest notes move up an octave
+    pub inversion: usize,
+    // pinching while pressing adds the seventh
+    pub pinch_seventh: bool,
+}
+
+impl ChordSettings {
+    // C major triads from C4, as the chord map always played
+    pub fn new() -> ChordSettings {
+        ChordSettings {
+            key: 0,
+            octave: 4,
+            scale: Scale::Major,
+            quality: ChordQuality::Triad,
+            voicing: Voicing::Close,
+            inversion: 0,
+            pinch_seventh: true,
+        }
+    }
+
+    pub fn key_name(&self) -> &'static str {
+        KEY_NAMES[self.key.rem_euclid(12) as usize]
+    }
+
+    pub fn next_key(&self) -> i32 {
+        (self.key + 1).rem_euclid(12)
+    }
+
+    pub fn next_inversion(&self) -> usize {
+        (self.inversion + 1) % self.quality.with_seventh().degrees().len()
+    }
+
+    // Frequencies of the chord on a scale degree, 0 being the key itself
+    pub fn chord(&self, degree: usize, seventh: bool) -> Vec<f32> {
+        let quality = if seventh { self.quality.with_seventh() } else { self.quality };
+        let root = 12 * (self.octave + 1) + self.key.rem_euclid(12);
+        let mut notes: Vec<i32> = quality.degrees().iter()
+            .map(|x| root + self.scale.semitones(degree + x))
+            .collect();
+        for index in 0..self.inversion.min(notes.len()) {
+            notes[index] += 12;
+        }
+        notes.sort();
+        match self.voicing {
+            Voicing::Close => {},
+            Voicing::Open => {
+                if notes.len() > 2 {
+                    notes[1] += 12;
+                }
+            },
+            Voicing::Spread => notes[0] -= 12,
+        }
+        notes.into_iter().map(|x| notefreq::midi_to_freq(x as f32)).collect()
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} {} {} {} inv {}", self.key_name(), self.scale, self.quality, self.voicing, self.inversion)
+    }
+
+    pub fn from_preset(preset: &Preset) -> ChordSettings {
+        let defaults = ChordSettings::new();
+        ChordSettings {
+            key: preset.get("chords.key").unwrap_or(defaults.key),
+            octave: preset.get("chords.octave").unwrap_or(defaults.octave),
+            scale: preset.get("chords.scale").unwrap_or(defaults.scale),
+            quality: preset.get("chords.quality").unwrap_or(defaults.quality),
+            voicing: preset.get("chords.voicing").unwrap_or(defaults.voicing),
+            inversion: preset.get("chords.inversion").unwrap_or(defaults.inversion),
+            pinch_seventh: preset.get("chords.pinch_seventh").unwrap_or(defaults.pinch_seventh),
+        }
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset) {
+        preset.set("chords.key", self.key);
+        preset.set("chords.octave", self.octave);
+        preset.set("chords.scale", self.scale);
+        preset.set("chords.quality", self.quality);
+        preset.set("chords.voicing", self.voicing);
+        preset.set("chords.inversion", self.inversion);
+        preset.set("chords.pinch_seventh", self.pinch_seventh);
+    }
+}
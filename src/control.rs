@@ -0,0 +1,105 @@
+use std::f32::consts::FRAC_PI_2;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::calibration::Calibration;
+use crate::leaprust::{LeapRustFinger, LeapRustHand};
+
+// Hand measurements that can continuously steer a playing note
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum ControlSource {
+    Off,
+    PalmX,
+    PalmY,
+    PalmZ,
+    PalmVelocityX,
+    PalmVelocityY,
+    PalmVelocityZ,
+    // tilting the palm side to side and front to back
+    PalmRoll,
+    PalmPitch,
+    FingerX,
+    FingerY,
+    FingerZ,
+    FingerVelocityX,
+    FingerVelocityY,
+    FingerVelocityZ,
+    Pinch,
+    Grab,
+    // size of the ball the hand is curled around
+    SphereRadius,
+}
+
+// mm/s, radians and mm
+const VELOCITY_RANGE: (f32, f32) = (-1000.0, 1000.0);
+const ANGLE_RANGE: (f32, f32) = (-FRAC_PI_2, FRAC_PI_2);
+const SPHERE_RANGE: (f32, f32) = (30.0, 150.0);
+
+fn normalize(value: f32, (low, high): (f32, f32)) -> f32 {
+    ((value - low) / (high - low)).max(0.0).min(1.0)
+}
+
+impl ControlSource {
+    pub const ALL: [ControlSource; 18] = [
+        ControlSource::Off,
+        ControlSource::PalmX,
+        ControlSource::PalmY,
+        ControlSource::PalmZ,
+        ControlSource::PalmVelocityX,
+        ControlSource::PalmVelocityY,
+        ControlSource::PalmVelocityZ,
+        ControlSource::PalmRoll,
+        ControlSource::PalmPitch,
+        ControlSource::FingerX,
+        ControlSource::FingerY,
+        ControlSource::FingerZ,
+        ControlSource::FingerVelocityX,
+        ControlSource::FingerVelocityY,
+        ControlSource::FingerVelocityZ,
+        ControlSource::Pinch,
+        ControlSource::Grab,
+        ControlSource::SphereRadius,
+    ];
+
+    // Reads the source scaled to 0..1, or None when it is switched off.
+    // Positions are scaled to the player's calibrated reach.
+    pub fn read(&self, hand: &LeapRustHand, finger: &LeapRustFinger, calibration: &Calibration) -> Option<f32> {
+        match self {
+            ControlSource::Off => None,
+            ControlSource::PalmX => Some(normalize(hand.palmPosition.x, calibration.x)),
+            ControlSource::PalmY => Some(normalize(hand.palmPosition.y, calibration.y)),
+            ControlSource::PalmZ => Some(normalize(hand.palmPosition.z, calibration.z)),
+            ControlSource::PalmVelocityX => Some(normalize(hand.palmVelocity.x, VELOCITY_RANGE)),
+            ControlSource::PalmVelocityY => Some(normalize(hand.palmVelocity.y, VELOCITY_RANGE)),
+            ControlSource::PalmVelocityZ => Some(normalize(hand.palmVelocity.z, VELOCITY_RANGE)),
+            ControlSource::PalmRoll => {
+                let roll = hand.palmNormal.x.atan2(-hand.palmNormal.y);
+                Some(normalize(roll, ANGLE_RANGE))
+            },
+            ControlSource::PalmPitch => {
+                let pitch = hand.direction.y.atan2(-hand.direction.z);
+                Some(normalize(pitch, ANGLE_RANGE))
+            },
+            ControlSource::FingerX => Some(normalize(finger.tipPosition.x, calibration.x)),
+            ControlSource::FingerY => Some(normalize(finger.tipPosition.y, calibration.y)),
+            ControlSource::FingerZ => Some(normalize(finger.tipPosition.z, calibration.z)),
+            ControlSource::FingerVelocityX => Some(normalize(finger.tipVelocity.x, VELOCITY_RANGE)),
+            ControlSource::FingerVelocityY => Some(normalize(finger.tipVelocity.y, VELOCITY_RANGE)),
+            ControlSource::FingerVelocityZ => Some(normalize(finger.tipVelocity.z, VELOCITY_RANGE)),
+            ControlSource::Pinch => Some(hand.pinchStrength.max(0.0).min(1.0)),
+            ControlSource::Grab => Some(hand.grabStrength.max(0.0).min(1.0)),
+            ControlSource::SphereRadius => Some(normalize(hand.sphereRadius, SPHERE_RANGE)),
+        }
+    }
+}
+
+impl FromStr for ControlSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ControlSource, String> {
+        ControlSource::ALL.iter()
+            .find(|x| x.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown control source {}", s))
+    }
+}
@@ -0,0 +1,368 @@
+use std::f32::consts::PI;
+use std::fmt::Display;
+
+use crate::preset::Preset;
+
+const MAX_DELAY_SECONDS: f32 = 2.0;
+const MAX_CHORUS_SECONDS: f32 = 0.05;
+const CHORUS_BASE_SECONDS: f32 = 0.015;
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum EffectParam {
+    ReverbMix,
+    ReverbSize,
+    ReverbDamping,
+    DelayMix,
+    DelayTime,
+    DelayFeedback,
+    DelaySync,
+    DelayBeats,
+    ChorusMix,
+    ChorusRate,
+    ChorusDepth,
+}
+
+impl EffectParam {
+    pub const ALL: [EffectParam; 11] = [
+        EffectParam::ReverbMix,
+        EffectParam::ReverbSize,
+        EffectParam::ReverbDamping,
+        EffectParam::DelayMix,
+        EffectParam::DelayTime,
+        EffectParam::DelayFeedback,
+        EffectParam::DelaySync,
+        EffectParam::DelayBeats,
+        EffectParam::ChorusMix,
+        EffectParam::ChorusRate,
+        EffectParam::ChorusDepth,
+    ];
+
+    pub fn range(&self) -> (f32, f32) {
+        match self {
+            EffectParam::DelayTime => (10.0, 2000.0),
+            EffectParam::DelayFeedback => (0.0, 0.95),
+            EffectParam::DelayBeats => (0.25, 4.0),
+            EffectParam::ChorusRate => (0.1, 5.0),
+            EffectParam::ChorusDepth => (0.0, 10.0),
+            _ => (0.0, 1.0),
+        }
+    }
+
+    pub fn from_normalized(&self, value: f32) -> f32 {
+        let (low, high) = self.range();
+        low + (high - low) * value.max(0.0).min(1.0)
+    }
+
+    pub fn to_normalized(&self, value: f32) -> f32 {
+        let (low, high) = self.range();
+        (value - low) / (high - low)
+    }
+
+    fn preset_key(&self) -> String {
+        format!("effects.{}", self)
+    }
+}
+
+// Every parameter of the master bus; delay time is in milliseconds, chorus
+// depth in milliseconds and rates in hertz. A synced delay follows the
+// transport's tempo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EffectSettings {
+    pub reverb_mix: f32,
+    pub reverb_size: f32,
+    pub reverb_damping: f32,
+    pub delay_mix: f32,
+    pub delay_time: f32,
+    pub delay_feedback: f32,
+    pub delay_sync: bool,
+    pub delay_beats: f32,
+    pub chorus_mix: f32,
+    pub chorus_rate: f32,
+    pub chorus_depth: f32,
+}
+
+impl EffectSettings {
+    pub fn new() -> EffectSettings {
+        EffectSettings {
+            reverb_mix: 0.2,
+            reverb_size: 0.5,
+            reverb_damping: 0.5,
+            delay_mix: 0.0,
+            delay_time: 375.0,
+            delay_feedback: 0.4,
+            delay_sync: false,
+            delay_beats: 0.75,
+            chorus_mix: 0.0,
+            chorus_rate: 0.8,
+            chorus_depth: 3.0,
+        }
+    }
+
+    pub fn get(&self, param: EffectParam) -> f32 {
+        match param {
+            EffectParam::ReverbMix => self.reverb_mix,
+            EffectParam::ReverbSize => self.reverb_size,
+            EffectParam::ReverbDamping => self.reverb_damping,
+            EffectParam::DelayMix => self.delay_mix,
+            EffectParam::DelayTime => self.delay_time,
+            EffectParam::DelayFeedback => self.delay_feedback,
+            EffectParam::DelaySync => if self.delay_sync { 1.0 } else { 0.0 },
+            EffectParam::DelayBeats => self.delay_beats,
+            EffectParam::ChorusMix => self.chorus_mix,
+            EffectParam::ChorusRate => self.chorus_rate,
+            EffectParam::ChorusDepth => self.chorus_depth,
+        }
+    }
+
+    pub fn set(&mut self, param: EffectParam, value: f32) {
+        let (low, high) = param.range();
+        let value = value.max(low).min(high);
+        match param {
+            EffectParam::ReverbMix => self.reverb_mix = value,
+            EffectParam::ReverbSize => self.reverb_size = value,
+            EffectParam::ReverbDamping => self.reverb_damping = value,
+            EffectParam::DelayMix => self.delay_mix = value,
+            EffectParam::DelayTime => self.delay_time = value,
+            EffectParam::DelayFeedback => self.delay_feedback = value,
+            EffectParam::DelaySync => self.delay_sync = value >= 0.5,
+            // synced delays snap to sixteenth notes
+            EffectParam::DelayBeats => self.delay_beats = (value * 4.0).round() / 4.0,
+            EffectParam::ChorusMix => self.chorus_mix = value,
+            EffectParam::ChorusRate => self.chorus_rate = value,
+            EffectParam::ChorusDepth => self.chorus_depth = value,
+        }
+    }
+
+    pub fn from_preset(preset: &Preset) -> EffectSettings {
+        let mut settings = EffectSettings::new();
+        for param in EffectParam::ALL {
+            if let Some(value) = preset.get(&param.preset_key()) {
+                settings.set(param, value);
+            }
+        }
+        settings
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset) {
+        for param in EffectParam::ALL {
+            preset.set(&param.preset_key(), self.get(param));
+        }
+    }
+
+    fn delay_seconds(&self, bpm: f32) -> f32 {
+        if self.delay_sync {
+            self.delay_beats * 60.0 / bpm
+        } else {
+            self.delay_time / 1000.0
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DelayLine {
+    buffer: Vec<f32>,
+    write: usize,
+}
+
+impl DelayLine {
+    fn new(len: usize) -> DelayLine {
+        DelayLine { buffer: vec![0f32; len.max(2)], write: 0 }
+    }
+
+    // delay is in samples and may be fractional
+    fn read(&self, delay: f32) -> f32 {
+        let len = self.buffer.len();
+        let delay = delay.max(1.0).min((len - 2) as f32);
+        let position = self.write as f32 + len as f32 - delay;
+        let first_index = position as usize % len;
+        let second_index = (first_index + 1) % len;
+        let second_weight = position - position.floor();
+        self.buffer[first_index] * (1.0 - second_weight) + self.buffer[second_index] * second_weight
+    }
+
+    fn write(&mut self, value: f32) {
+        self.buffer[self.write] = value;
+        self.write = (self.write + 1) % self.buffer.len();
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Comb {
+    line: DelayLine,
+    filter_store: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let len = self.line.buffer.len();
+        let output = self.line.buffer[self.line.write % len];
+        self.filter_store = output * (1.0 - damping) + self.filter_store * damping;
+        self.line.write(input + self.filter_store * feedback);
+        output
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Allpass {
+    line: DelayLine,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let len = self.line.buffer.len();
+        let delayed = self.line.buffer[self.line.write % len];
+        self.line.write(input + delayed * 0.5);
+        delayed - input
+    }
+}
+
+// Schroeder/Moorer reverb in the Freeverb arrangement: parallel damped combs
+// into series allpasses, with the right channel's lines slightly longer.
+#[derive(Debug, Clone)]
+struct Reverb {
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+}
+
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+
+impl Reverb {
+    fn new(sample_rate: u32) -> Reverb {
+        let scale = |samples: usize| samples * sample_rate as usize / 44100;
+        let channel = |spread: usize| {
+            let combs = COMB_TUNINGS.iter()
+                .map(|x| Comb { line: DelayLine::new(scale(x + spread)), filter_store: 0.0 })
+                .collect::<Vec<_>>();
+            let allpasses = ALLPASS_TUNINGS.iter()
+                .map(|x| Allpass { line: DelayLine::new(scale(x + spread)) })
+                .collect::<Vec<_>>();
+            (combs, allpasses)
+        };
+        let (left_combs, left_allpasses) = channel(0);
+        let (right_combs, right_allpasses) = channel(STEREO_SPREAD);
+        Reverb {
+            combs: [left_combs, right_combs],
+            allpasses: [left_allpasses, right_allpasses],
+        }
+    }
+
+    fn process(&mut self, input: f32, settings: &EffectSettings) -> (f32, f32) {
+        let feedback = settings.reverb_size * 0.28 + 0.7;
+        let damping = settings.reverb_damping * 0.4;
+        let input = input * 0.015;
+        let mut out = [0f32; 2];
+        for channel in 0..2 {
+            let mut sum = 0f32;
+            for comb in &mut self.combs[channel] {
+                sum += comb.process(input, feedback, damping);
+            }
+            for allpass in &mut self.allpasses[channel] {
+                sum = allpass.process(sum);
+            }
+            out[channel] = sum;
+        }
+        (out[0] * 3.0, out[1] * 3.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Delay {
+    lines: [DelayLine; 2],
+}
+
+impl Delay {
+    fn process(&mut self, (left, right): (f32, f32), settings: &EffectSettings, sample_rate: u32, bpm: f32) -> (f32, f32) {
+        let delay = settings.delay_seconds(bpm) * sample_rate as f32;
+        let delayed_left = self.lines[0].read(delay);
+        let delayed_right = self.lines[1].read(delay);
+        self.lines[0].write(left + delayed_left * settings.delay_feedback);
+        self.lines[1].write(right + delayed_right * settings.delay_feedback);
+        (delayed_left, delayed_right)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Chorus {
+    lines: [DelayLine; 2],
+    phase: f32,
+}
+
+impl Chorus {
+    fn process(&mut self, (left, right): (f32, f32), settings: &EffectSettings, sample_rate: u32) -> (f32, f32) {
+        let depth = settings.chorus_depth / 1000.0;
+        // the channels' sweeps are a quarter cycle apart to widen the image
+        let sweep = |offset: f32| {
+            let seconds = CHORUS_BASE_SECONDS + depth * 0.5 * (1.0 + (2.0 * PI * (self.phase + offset)).sin());
+            seconds * sample_rate as f32
+        };
+        let (left_delay, right_delay) = (sweep(0.0), sweep(0.25));
+        self.lines[0].write(left);
+        self.lines[1].write(right);
+        self.phase = (self.phase + settings.chorus_rate / sample_rate as f32) % 1.0;
+        (self.lines[0].read(left_delay), self.lines[1].read(right_delay))
+    }
+}
+
+fn mix(dry: (f32, f32), wet: (f32, f32), amount: f32) -> (f32, f32) {
+    (
+        dry.0 * (1.0 - amount) + wet.0 * amount,
+        dry.1 * (1.0 - amount) + wet.1 * amount,
+    )
+}
+
+// Chorus, then delay, then reverb over the mixed voices
+#[derive(Debug, Clone)]
+pub struct EffectsChain {
+    pub settings: EffectSettings,
+    // modulation added on top of the reverb, delay and chorus mixes
+    sends: (f32, f32, f32),
+    sample_rate: u32,
+    chorus: Chorus,
+    delay: Delay,
+    reverb: Reverb,
+}
+
+impl EffectsChain {
+    pub fn new(settings: EffectSettings, sample_rate: u32) -> EffectsChain {
+        let samples = |seconds: f32| (seconds * sample_rate as f32) as usize + 2;
+        EffectsChain {
+            settings: settings,
+            sends: (0.0, 0.0, 0.0),
+            sample_rate: sample_rate,
+            chorus: Chorus {
+                lines: [DelayLine::new(samples(MAX_CHORUS_SECONDS)), DelayLine::new(samples(MAX_CHORUS_SECONDS))],
+                phase: 0.0,
+            },
+            delay: Delay {
+                lines: [DelayLine::new(samples(MAX_DELAY_SECONDS)), DelayLine::new(samples(MAX_DELAY_SECONDS))],
+            },
+            reverb: Reverb::new(sample_rate),
+        }
+    }
+
+    pub fn set_sends(&mut self, reverb: f32, delay: f32, chorus: f32) {
+        self.sends = (reverb, delay, chorus);
+    }
+
+    // bpm is the transport's, for a synced delay
+    pub fn process(&mut self, block: &mut [(f32, f32)], bpm: f32) {
+        let mut settings = self.settings;
+        settings.set(EffectParam::ReverbMix, settings.reverb_mix + self.sends.0);
+        settings.set(EffectParam::DelayMix, settings.delay_mix + self.sends.1);
+        settings.set(EffectParam::ChorusMix, settings.chorus_mix + self.sends.2);
+        for frame in block.iter_mut() {
+            let mut val = *frame;
+            if settings.chorus_mix > 0.0 {
+                let wet = self.chorus.process(val, &settings, self.sample_rate);
+                val = mix(val, wet, settings.chorus_mix);
+            }
+            let wet = self.delay.process(val, &settings, self.sample_rate, bpm);
+            val = mix(val, wet, settings.delay_mix);
+            let wet = self.reverb.process((val.0 + val.1) / 2.0, &settings);
+            val = mix(val, wet, settings.reverb_mix);
+            *frame = val;
+        }
+    }
+}
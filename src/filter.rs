@@ -0,0 +1,208 @@
+use std::f32::consts::PI;
+use std::fmt::Display;
+
+use crate::calibration::Calibration;
+use crate::control::ControlSource;
+use crate::leaprust::{LeapRustFinger, LeapRustHand};
+
+const MIN_CUTOFF: f32 = 20.0;
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum FilterMode {
+    Lowpass,
+    Highpass,
+    Bandpass,
+}
+
+// Times in seconds, sustain as a 0..1 level
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnvelopeSettings {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterSettings {
+    pub mode: FilterMode,
+    pub cutoff: f32,
+    // 0 is flat, 1 self oscillates
+    pub resonance: f32,
+    pub envelope: EnvelopeSettings,
+    // octaves the envelope opens the cutoff by at full level
+    pub envelope_amount: f32,
+    pub cutoff_source: ControlSource,
+    // octaves the cutoff source sweeps across its range
+    pub cutoff_range: f32,
+    pub resonance_source: ControlSource,
+    // octaves a full velocity note opens the cutoff by
+    pub velocity_amount: f32,
+}
+
+impl FilterSettings {
+    pub fn new(mode: FilterMode, cutoff: f32, resonance: f32) -> FilterSettings {
+        FilterSettings {
+            mode: mode,
+            cutoff: cutoff,
+            resonance: resonance,
+            envelope: EnvelopeSettings { attack: 0.01, decay: 0.3, sustain: 0.0, release: 0.3 },
+            envelope_amount: 0.0,
+            cutoff_source: ControlSource::Off,
+            cutoff_range: 0.0,
+            resonance_source: ControlSource::Off,
+            velocity_amount: 0.0,
+        }
+    }
+
+    pub fn with_envelope(mut self, envelope: EnvelopeSettings, amount: f32) -> FilterSettings {
+        self.envelope = envelope;
+        self.envelope_amount = amount;
+        self
+    }
+
+    pub fn with_cutoff_source(mut self, source: ControlSource, range: f32) -> FilterSettings {
+        self.cutoff_source = source;
+        self.cutoff_range = range;
+        self
+    }
+
+    pub fn with_resonance_source(mut self, source: ControlSource) -> FilterSettings {
+        self.resonance_source = source;
+        self
+    }
+
+    pub fn with_velocity(mut self, amount: f32) -> FilterSettings {
+        self.velocity_amount = amount;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EnvelopeStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Envelope {
+    stage: EnvelopeStage,
+    level: f32,
+}
+
+impl Envelope {
+    fn next(&mut self, settings: &EnvelopeSettings, sample_rate: u32) -> f32 {
+        let step = |seconds: f32| 1.0 / (seconds.max(0.0001) * sample_rate as f32);
+        match self.stage {
+            EnvelopeStage::Attack => {
+                self.level += step(settings.attack);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = EnvelopeStage::Decay;
+                }
+            },
+            EnvelopeStage::Decay => {
+                self.level -= step(settings.decay) * (1.0 - settings.sustain);
+                if self.level <= settings.sustain {
+                    self.level = settings.sustain;
+                    self.stage = EnvelopeStage::Sustain;
+                }
+            },
+            EnvelopeStage::Sustain => {},
+            EnvelopeStage::Release => {
+                self.level = (self.level - step(settings.release)).max(0.0);
+            },
+        }
+        self.level
+    }
+}
+
+// Trapezoidal state variable filter (Andrew Simper's formulation), which
+// stays stable while the cutoff is swept every sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct StateVariableFilter {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl StateVariableFilter {
+    fn process(&mut self, input: f32, mode: FilterMode, cutoff: f32, resonance: f32, sample_rate: u32) -> f32 {
+        let cutoff = cutoff.max(MIN_CUTOFF).min(sample_rate as f32 * 0.45);
+        let g = (PI * cutoff / sample_rate as f32).tan();
+        let k = 2.0 - 2.0 * resonance.max(0.0).min(0.98);
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+        match mode {
+            FilterMode::Lowpass => v2,
+            FilterMode::Bandpass => v1,
+            FilterMode::Highpass => input - k * v1 - v2,
+        }
+    }
+}
+
+// Filter owned by a single note
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoiceFilter {
+    settings: FilterSettings,
+    svf: StateVariableFilter,
+    envelope: Envelope,
+    cutoff_control: f32,
+    // octaves added by the modulation matrix
+    cutoff_modulation: f32,
+    velocity: f32,
+    resonance: f32,
+}
+
+impl VoiceFilter {
+    pub fn new(settings: FilterSettings) -> VoiceFilter {
+        VoiceFilter {
+            settings: settings,
+            svf: StateVariableFilter { ic1eq: 0.0, ic2eq: 0.0 },
+            envelope: Envelope { stage: EnvelopeStage::Attack, level: 0.0 },
+            cutoff_control: 0.0,
+            cutoff_modulation: 0.0,
+            velocity: 0.0,
+            resonance: settings.resonance,
+        }
+    }
+
+    pub fn update_controls(&mut self, hand: &LeapRustHand, finger: &LeapRustFinger, calibration: &Calibration) {
+        if let Some(value) = self.settings.cutoff_source.read(hand, finger, calibration) {
+            self.cutoff_control = value;
+        }
+        if let Some(value) = self.settings.resonance_source.read(hand, finger, calibration) {
+            self.resonance = value;
+        }
+    }
+
+    // 0..1, how hard the note was struck
+    pub fn set_velocity(&mut self, velocity: f32) {
+        self.velocity = velocity;
+    }
+
+    pub fn set_cutoff_modulation(&mut self, octaves: f32) {
+        self.cutoff_modulation = octaves;
+    }
+
+    pub fn release(&mut self) {
+        self.envelope.stage = EnvelopeStage::Release;
+    }
+
+    pub fn process(&mut self, input: f32, sample_rate: u32) -> f32 {
+        let envelope = self.envelope.next(&self.settings.envelope, sample_rate);
+        let octaves = envelope * self.settings.envelope_amount
+            + self.cutoff_control * self.settings.cutoff_range
+            + self.cutoff_modulation
+            + self.velocity * self.settings.velocity_amount;
+        let cutoff = self.settings.cutoff * 2f32.powf(octaves);
+        self.svf.process(input, self.settings.mode, cutoff, self.resonance, sample_rate)
+    }
+}
@@ -0,0 +1,126 @@
+use std::f32::consts::PI;
+use std::fmt::Display;
+
+pub const MAX_OPERATORS: usize = 4;
+
+// How the operators feed each other; operator 0 is always a carrier.
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum FmAlgorithm {
+    // 3 -> 2 -> 1 -> 0
+    Stack,
+    // 1 -> 0 and 3 -> 2, both pairs heard
+    Pairs,
+    // 1, 2 and 3 all modulate 0
+    Branch,
+    // every operator heard on its own
+    Additive,
+}
+
+impl FmAlgorithm {
+    fn modulators(&self, operator: usize) -> &'static [usize] {
+        match (self, operator) {
+            (FmAlgorithm::Stack, 0) => &[1],
+            (FmAlgorithm::Stack, 1) => &[2],
+            (FmAlgorithm::Stack, 2) => &[3],
+            (FmAlgorithm::Pairs, 0) => &[1],
+            (FmAlgorithm::Pairs, 2) => &[3],
+            (FmAlgorithm::Branch, 0) => &[1, 2, 3],
+            _ => &[],
+        }
+    }
+
+    fn is_carrier(&self, operator: usize) -> bool {
+        match self {
+            FmAlgorithm::Stack | FmAlgorithm::Branch => operator == 0,
+            FmAlgorithm::Pairs => operator == 0 || operator == 2,
+            FmAlgorithm::Additive => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FmOperator {
+    // frequency as a multiple of the note frequency
+    pub ratio: f32,
+    // peak phase deviation in radians when modulating, level when a carrier
+    pub index: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FmPatch {
+    pub algorithm: FmAlgorithm,
+    pub operators: Vec<FmOperator>,
+    // self modulation of the last operator
+    pub feedback: f32,
+}
+
+impl FmPatch {
+    pub fn new(algorithm: FmAlgorithm, operators: &[(f32, f32)]) -> FmPatch {
+        assert!(operators.len() >= 1 && operators.len() <= MAX_OPERATORS);
+        FmPatch {
+            algorithm: algorithm,
+            operators: operators.iter().map(|&(ratio, index)| FmOperator { ratio, index }).collect(),
+            feedback: 0.0,
+        }
+    }
+
+    pub fn with_feedback(mut self, feedback: f32) -> FmPatch {
+        self.feedback = feedback;
+        self
+    }
+
+    // A bright electric piano-ish pair
+    pub fn two_operator() -> FmPatch {
+        FmPatch::new(FmAlgorithm::Stack, &[(1.0, 1.0), (1.0, 3.0)])
+    }
+
+    // A brassy four operator stack
+    pub fn four_operator() -> FmPatch {
+        FmPatch::new(FmAlgorithm::Stack, &[(1.0, 1.0), (1.0, 2.5), (2.0, 1.5), (3.0, 1.0)])
+            .with_feedback(0.3)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FmState {
+    phases: [f32; MAX_OPERATORS],
+    outputs: [f32; MAX_OPERATORS],
+}
+
+impl FmState {
+    pub fn new() -> FmState {
+        FmState {
+            phases: [0f32; MAX_OPERATORS],
+            outputs: [0f32; MAX_OPERATORS],
+        }
+    }
+
+    // index_scale multiplies every modulator's index, so 0 leaves only the
+    // carriers' sine waves
+    pub fn sample(&mut self, patch: &FmPatch, freq: f32, sample_rate: u32, index_scale: f32) -> f32 {
+        let count = patch.operators.len();
+        let mut sum = 0f32;
+        let mut carriers = 0;
+        // modulators always have a higher number than what they modulate
+        for operator in (0..count).rev() {
+            let settings = patch.operators[operator];
+            let mut modulation: f32 = patch.algorithm.modulators(operator).iter()
+                .filter(|&&x| x < count)
+                .map(|&x| self.outputs[x])
+                .sum();
+            if operator == count - 1 {
+                modulation += self.outputs[operator] * patch.feedback;
+            }
+            let val = (2.0 * PI * self.phases[operator] + modulation).sin();
+            if patch.algorithm.is_carrier(operator) {
+                self.outputs[operator] = val;
+                sum += val * settings.index;
+                carriers += 1;
+            } else {
+                self.outputs[operator] = val * settings.index * index_scale;
+            }
+            self.phases[operator] = (self.phases[operator] + freq * settings.ratio / sample_rate as f32) % 1.0;
+        }
+        sum / carriers.max(1) as f32
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::handview::{HandRole, HandSettings, HandView, Side};
+use crate::leaprust::{LeapRustHand, LeapRustVector, LeapRustFingerType_TYPE_INDEX};
+use crate::preset::Preset;
+
+// Frames are timestamped in microseconds
+const HISTORY: i32 = 1_000_000;
+const COOLDOWN: i32 = 400_000;
+// palm speed in mm/s and distance in mm a swipe has to reach
+const SWIPE_SPEED: f32 = 1000.0;
+const SWIPE_DISTANCE: f32 = 150.0;
+const SWIPE_TIME: i32 = 300_000;
+// a tap pokes the index finger forward quickly and stops
+const TAP_SPEED: f32 = 600.0;
+const TAP_STOP_SPEED: f32 = 100.0;
+const TAP_TIME: i32 = 200_000;
+// a circle drawn with the index finger has to go all the way round
+const CIRCLE_MIN_RADIUS: f32 = 20.0;
+const PINCH_ON: f32 = 0.9;
+const PINCH_OFF: f32 = 0.5;
+const FIST_ON: f32 = 0.95;
+const FIST_OFF: f32 = 0.6;
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum Gesture {
+    SwipeLeft,
+    SwipeRight,
+    SwipeUp,
+    SwipeDown,
+    Circle,
+    Tap,
+    PinchClick,
+    Fist,
+}
+
+impl Gesture {
+    pub const ALL: [Gesture; 8] = [
+        Gesture::SwipeLeft,
+        Gesture::SwipeRight,
+        Gesture::SwipeUp,
+        Gesture::SwipeDown,
+        Gesture::Circle,
+        Gesture::Tap,
+        Gesture::PinchClick,
+        Gesture::Fist,
+    ];
+}
+
+impl FromStr for Gesture {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Gesture, String> {
+        Gesture::ALL.iter()
+            .find(|x| x.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown gesture {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum GestureAction {
+    Nothing,
+    NextShape,
+    NextMap,
+    PreviousMap,
+    OctaveUp,
+    OctaveDown,
+    ToggleSustain,
+}
+
+impl GestureAction {
+    pub const ALL: [GestureAction; 7] = [
+        GestureAction::Nothing,
+        GestureAction::NextShape,
+        GestureAction::NextMap,
+        GestureAction::PreviousMap,
+        GestureAction::OctaveUp,
+        GestureAction::OctaveDown,
+        GestureAction::ToggleSustain,
+    ];
+}
+
+impl FromStr for GestureAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<GestureAction, String> {
+        GestureAction::ALL.iter()
+            .find(|x| x.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown gesture action {}", s))
+    }
+}
+
+// What each gesture does, stored as gesture.<Gesture> = <Action>
+#[derive(Debug, Clone, PartialEq)]
+pub struct GestureBindings {
+    actions: Vec<(Gesture, GestureAction)>,
+}
+
+impl GestureBindings {
+    pub fn new() -> GestureBindings {
+        GestureBindings {
+            actions: vec!(
+                (Gesture::SwipeLeft, GestureAction::PreviousMap),
+                (Gesture::SwipeRight, GestureAction::NextMap),
+                (Gesture::SwipeUp, GestureAction::OctaveUp),
+                (Gesture::SwipeDown, GestureAction::OctaveDown),
+                (Gesture::Circle, GestureAction::NextShape),
+            ),
+        }
+    }
+
+    pub fn action(&self, gesture: Gesture) -> GestureAction {
+        self.actions.iter()
+            .find(|x| x.0 == gesture)
+            .map_or(GestureAction::Nothing, |x| x.1)
+    }
+
+    pub fn bind(&mut self, gesture: Gesture, action: GestureAction) {
+        self.actions.retain(|x| x.0 != gesture);
+        self.actions.push((gesture, action));
+    }
+
+    pub fn from_preset(preset: &Preset) -> GestureBindings {
+        let mut bindings = GestureBindings::new();
+        for gesture in Gesture::ALL {
+            if let Some(action) = preset.get(&format!("gesture.{}", gesture)) {
+                bindings.bind(gesture, action);
+            }
+        }
+        bindings
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset) {
+        for gesture in Gesture::ALL {
+            preset.set(&format!("gesture.{}", gesture), self.action(gesture));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HandSample {
+    timestamp: i32,
+    palm: LeapRustVector,
+    palm_velocity: LeapRustVector,
+    index_tip: Option<LeapRustVector>,
+    index_velocity: Option<LeapRustVector>,
+}
+
+// Recent movement of one hand
+#[derive(Debug, Clone)]
+struct HandTrack {
+    history: VecDeque<HandSample>,
+    pinched: bool,
+    fist: bool,
+    // no gestures until this time, so one movement isn't read twice
+    quiet_until: Option<i32>,
+}
+
+impl HandTrack {
+    fn new() -> HandTrack {
+        HandTrack {
+            history: VecDeque::new(),
+            pinched: false,
+            fist: false,
+            quiet_until: None,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.history.clear();
+        self.pinched = false;
+        self.fist = false;
+    }
+
+    fn since(&self, timestamp: i32, age: i32) -> impl Iterator<Item = &HandSample> {
+        self.history.iter().filter(move |x| timestamp.wrapping_sub(x.timestamp) <= age)
+    }
+
+    fn update(&mut self, hand: &LeapRustHand, timestamp: i32) -> Option<Gesture> {
+        let index = hand.finger(LeapRustFingerType_TYPE_INDEX);
+        self.history.push_back(HandSample {
+            timestamp: timestamp,
+            palm: hand.palmPosition,
+            palm_velocity: hand.palmVelocity,
+            index_tip: index.map(|x| x.tipPosition),
+            index_velocity: index.map(|x| x.tipVelocity),
+        });
+        while self.history.front().map_or(false, |x| timestamp.wrapping_sub(x.timestamp) > HISTORY) {
+            self.history.pop_front();
+        }
+
+        // grabs and pinches are edges, so they're tracked even while quiet
+        let gesture = self.pinch(hand.pinchStrength).or(self.grab(hand.grabStrength));
+        let quiet = self.quiet_until.map_or(false, |x| timestamp.wrapping_sub(x) < 0);
+        if quiet {
+            return None;
+        }
+        let gesture = gesture
+            .or_else(|| self.swipe(timestamp))
+            .or_else(|| self.tap(timestamp))
+            .or_else(|| self.circle());
+        if gesture.is_some() {
+            self.quiet_until = Some(timestamp.wrapping_add(COOLDOWN));
+            self.history.clear();
+        }
+        gesture
+    }
+
+    fn pinch(&mut self, strength: f32) -> Option<Gesture> {
+        if !self.pinched && strength > PINCH_ON {
+            self.pinched = true;
+            return Some(Gesture::PinchClick);
+        }
+        if self.pinched && strength < PINCH_OFF {
+            self.pinched = false;
+        }
+        None
+    }
+
+    fn grab(&mut self, strength: f32) -> Option<Gesture> {
+        if !self.fist && strength > FIST_ON {
+            self.fist = true;
+            return Some(Gesture::Fist);
+        }
+        if self.fist && strength < FIST_OFF {
+            self.fist = false;
+        }
+        None
+    }
+
+    // Fast palm movement that has covered enough ground along one axis
+    fn swipe(&self, timestamp: i32) -> Option<Gesture> {
+        let latest = self.history.back()?;
+        let earliest = self.since(timestamp, SWIPE_TIME).next()?;
+        let dx = latest.palm.x - earliest.palm.x;
+        let dy = latest.palm.y - earliest.palm.y;
+        let velocity = latest.palm_velocity;
+        if dx.abs() > dy.abs() {
+            if dx.abs() > SWIPE_DISTANCE && velocity.x.abs() > SWIPE_SPEED {
+                return Some(if dx > 0.0 { Gesture::SwipeRight } else { Gesture::SwipeLeft });
+            }
+        } else if dy.abs() > SWIPE_DISTANCE && velocity.y.abs() > SWIPE_SPEED {
+            return Some(if dy > 0.0 { Gesture::SwipeUp } else { Gesture::SwipeDown });
+        }
+        None
+    }
+
+    // The index finger jabbed forward and has just stopped
+    fn tap(&self, timestamp: i32) -> Option<Gesture> {
+        let latest = self.history.back()?.index_velocity?;
+        let stopped = latest.z.abs() < TAP_STOP_SPEED;
+        let jabbed = self.since(timestamp, TAP_TIME)
+            .filter_map(|x| x.index_velocity)
+            .any(|x| x.z < -TAP_SPEED);
+        if stopped && jabbed { Some(Gesture::Tap) } else { None }
+    }
+
+    // The index fingertip has gone a full turn around the middle of its path,
+    // seen from in front
+    fn circle(&self) -> Option<Gesture> {
+        let tips: Vec<LeapRustVector> = self.history.iter().filter_map(|x| x.index_tip).collect();
+        if tips.len() < 10 {
+            return None;
+        }
+        let count = tips.len() as f32;
+        let centre_x = tips.iter().map(|x| x.x).sum::<f32>() / count;
+        let centre_y = tips.iter().map(|x| x.y).sum::<f32>() / count;
+        let radius = tips.iter()
+            .map(|x| ((x.x - centre_x).powi(2) + (x.y - centre_y).powi(2)).sqrt())
+            .sum::<f32>() / count;
+        if radius < CIRCLE_MIN_RADIUS {
+            return None;
+        }
+        let angles: Vec<f32> = tips.iter().map(|x| (x.y - centre_y).atan2(x.x - centre_x)).collect();
+        let turned: f32 = angles.windows(2)
+            .map(|pair| {
+                let step = pair[1] - pair[0];
+                if step > PI { step - 2.0 * PI } else if step < -PI { step + 2.0 * PI } else { step }
+            })
+            .sum();
+        if turned.abs() >= 2.0 * PI { Some(Gesture::Circle) } else { None }
+    }
+}
+
+// Watches the hands that aren't playing notes for gestures over the last
+// second of frames, so playing doesn't set them off
+#[derive(Debug, Clone)]
+pub struct GestureRecognizer {
+    left: HandTrack,
+    right: HandTrack,
+}
+
+impl GestureRecognizer {
+    pub fn new() -> GestureRecognizer {
+        GestureRecognizer { left: HandTrack::new(), right: HandTrack::new() }
+    }
+
+    pub fn update(&mut self, hands: &HandView, settings: &HandSettings) -> Vec<(Side, Gesture)> {
+        let mut gestures = Vec::new();
+        for side in [Side::Left, Side::Right] {
+            let track = match side {
+                Side::Left => &mut self.left,
+                Side::Right => &mut self.right,
+            };
+            let hand = hands.hand(side).filter(|_| settings.role(side) != HandRole::Play);
+            match hand {
+                Some(hand) => {
+                    if let Some(gesture) = track.update(hand, hands.timestamp) {
+                        gestures.push((side, gesture));
+                    }
+                },
+                None => track.clear(),
+            }
+        }
+        gestures
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::leaprust::{
+    LeapRustFinger,
+    LeapRustFingerType,
+    LeapRustFingerType_TYPE_INDEX,
+    LeapRustFingerType_TYPE_MIDDLE,
+    LeapRustFingerType_TYPE_PINKY,
+    LeapRustFingerType_TYPE_RING,
+    LeapRustFingerType_TYPE_THUMB,
+    LeapRustFrame,
+    LeapRustHand,
+};
+use crate::preset::Preset;
+
+#[derive(Debug, Clone, Copy, Display, PartialEq, Eq, Hash)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, Display, PartialEq, Eq, Hash)]
+pub enum Finger {
+    Thumb,
+    Index,
+    Middle,
+    Ring,
+    Little
+}
+
+impl Finger {
+    pub const ALL: [Finger; 5] = [Finger::Thumb, Finger::Index, Finger::Middle, Finger::Ring, Finger::Little];
+
+    // Position from thumb to little finger, as used by calibration
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn leap_type(&self) -> LeapRustFingerType {
+        match self {
+            Finger::Thumb => LeapRustFingerType_TYPE_THUMB,
+            Finger::Index => LeapRustFingerType_TYPE_INDEX,
+            Finger::Middle => LeapRustFingerType_TYPE_MIDDLE,
+            Finger::Ring => LeapRustFingerType_TYPE_RING,
+            Finger::Little => LeapRustFingerType_TYPE_PINKY,
+        }
+    }
+}
+
+// A frame with its hands sorted into left and right, so nothing downstream
+// depends on which order the controller reported them in
+#[derive(Debug, Clone, Copy)]
+pub struct HandView<'a> {
+    pub timestamp: i32,
+    left: Option<&'a LeapRustHand>,
+    right: Option<&'a LeapRustHand>,
+}
+
+impl<'a> HandView<'a> {
+    pub fn new(frame: &'a LeapRustFrame) -> HandView<'a> {
+        let count = (frame.handCount.max(0) as usize).min(frame.hands.len());
+        let hands = &frame.hands[..count];
+        HandView {
+            timestamp: frame.timestamp,
+            left: hands.iter().find(|x| x.isLeft != 0),
+            right: hands.iter().find(|x| x.isLeft == 0),
+        }
+    }
+
+    pub fn hand(&self, side: Side) -> Option<&'a LeapRustHand> {
+        match side {
+            Side::Left => self.left,
+            Side::Right => self.right,
+        }
+    }
+
+    pub fn left(&self) -> Option<&'a LeapRustHand> {
+        self.left
+    }
+
+    pub fn right(&self) -> Option<&'a LeapRustHand> {
+        self.right
+    }
+
+    pub fn finger(&self, side: Side, finger: Finger) -> Option<&'a LeapRustFinger> {
+        self.hand(side).and_then(|x| x.finger(finger.leap_type()))
+    }
+}
+
+impl Side {
+    pub fn other(&self) -> Side {
+        match self {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        }
+    }
+}
+
+// What a hand is used for
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum HandRole {
+    // fingers play notes
+    Play,
+    // fingers pick the note map
+    MapSelect,
+    // wrist angle picks the note map
+    WristSelect,
+    // steers the other hand's notes through the modulation matrix
+    Modulation,
+    Off,
+}
+
+impl HandRole {
+    pub const ALL: [HandRole; 5] = [
+        HandRole::Play,
+        HandRole::MapSelect,
+        HandRole::WristSelect,
+        HandRole::Modulation,
+        HandRole::Off,
+    ];
+
+    pub fn next(&self) -> HandRole {
+        let index = HandRole::ALL.iter().position(|x| x == self).unwrap_or(0);
+        HandRole::ALL[(index + 1) % HandRole::ALL.len()]
+    }
+}
+
+impl FromStr for HandRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HandRole, String> {
+        HandRole::ALL.iter()
+            .find(|x| x.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown hand role {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HandSettings {
+    pub left: HandRole,
+    pub right: HandRole,
+    // a playing left hand has its little finger on the lowest note, like
+    // the right hand's thumb
+    pub mirror_left: bool,
+    // octaves a playing left hand is shifted by
+    pub left_octave: i32,
+}
+
+impl HandSettings {
+    pub fn new() -> HandSettings {
+        HandSettings {
+            left: HandRole::WristSelect,
+            right: HandRole::Play,
+            mirror_left: true,
+            left_octave: -1,
+        }
+    }
+
+    pub fn role(&self, side: Side) -> HandRole {
+        match side {
+            Side::Left => self.left,
+            Side::Right => self.right,
+        }
+    }
+
+    pub fn set_role(&mut self, side: Side, role: HandRole) {
+        match side {
+            Side::Left => self.left = role,
+            Side::Right => self.right = role,
+        }
+    }
+
+    // The finger of the right handed layout a finger plays
+    pub fn layout_finger(&self, side: Side, finger: Finger) -> Finger {
+        if side == Side::Left && self.mirror_left {
+            Finger::ALL[Finger::ALL.len() - 1 - finger.index()]
+        } else {
+            finger
+        }
+    }
+
+    pub fn transpose(&self, side: Side) -> f32 {
+        match side {
+            Side::Left => 2f32.powi(self.left_octave),
+            Side::Right => 1.0,
+        }
+    }
+
+    // The hand that steers notes played by side: the other hand when it is
+    // there to modulate, otherwise the playing hand itself
+    pub fn control_side(&self, hands: &HandView, side: Side) -> Side {
+        let other = side.other();
+        if self.role(other) == HandRole::Modulation && hands.hand(other).is_some() {
+            other
+        } else {
+            side
+        }
+    }
+
+    pub fn from_preset(preset: &Preset) -> HandSettings {
+        let defaults = HandSettings::new();
+        HandSettings {
+            left: preset.get("hands.left").unwrap_or(defaults.left),
+            right: preset.get("hands.right").unwrap_or(defaults.right),
+            mirror_left: preset.get("hands.mirror_left").unwrap_or(defaults.mirror_left),
+            left_octave: preset.get("hands.left_octave").unwrap_or(defaults.left_octave),
+        }
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset) {
+        preset.set("hands.left", self.left);
+        preset.set("hands.right", self.right);
+        preset.set("hands.mirror_left", self.mirror_left);
+        preset.set("hands.left_octave", self.left_octave);
+    }
+}
@@ -1,2 +1,10 @@
 #![allow(non_snake_case, non_camel_case_types, non_upper_case_globals, dead_code)]
-include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
\ No newline at end of file
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+impl LeapRustHand {
+    // The shim doesn't promise any order, so fingers are found by type
+    pub fn finger(&self, finger_type: LeapRustFingerType) -> Option<&LeapRustFinger> {
+        let count = (self.fingerCount.max(0) as usize).min(self.fingers.len());
+        self.fingers[..count].iter().find(|x| x.type_ == finger_type)
+    }
+}
@@ -0,0 +1,134 @@
+use std::f32::consts::PI;
+use std::fmt::Display;
+
+use crate::leaprust::{LeapRustFinger, LeapRustHand};
+
+// Side to side speed, in mm/s, that counts as a full depth wiggle
+const FULL_WIGGLE_SPEED: f32 = 300.0;
+// Per frame smoothing: how fast the resting drift and the wiggle level follow
+const DRIFT_SMOOTHING: f32 = 0.05;
+const WIGGLE_SMOOTHING: f32 = 0.1;
+
+// What sets how deep the tremolo and vibrato go
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum DepthSource {
+    // always the full configured depth
+    Constant,
+    // wiggling the playing fingertip
+    Finger,
+    // shaking the whole hand
+    Palm,
+}
+
+// Rates in hertz; tremolo depth as a fraction of the volume, vibrato depth
+// in semitones either side of the note.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModulationSettings {
+    pub tremolo_rate: f32,
+    pub tremolo_depth: f32,
+    pub vibrato_rate: f32,
+    pub vibrato_depth: f32,
+    pub depth_source: DepthSource,
+}
+
+impl ModulationSettings {
+    pub fn tremolo(rate: f32, depth: f32, depth_source: DepthSource) -> ModulationSettings {
+        ModulationSettings {
+            tremolo_rate: rate,
+            tremolo_depth: depth,
+            vibrato_rate: 0.0,
+            vibrato_depth: 0.0,
+            depth_source: depth_source,
+        }
+    }
+
+    pub fn vibrato(rate: f32, depth: f32, depth_source: DepthSource) -> ModulationSettings {
+        ModulationSettings {
+            tremolo_rate: 0.0,
+            tremolo_depth: 0.0,
+            vibrato_rate: rate,
+            vibrato_depth: depth,
+            depth_source: depth_source,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lfo {
+    pub rate: f32,
+    phase: f32,
+}
+
+impl Lfo {
+    pub fn new(rate: f32) -> Lfo {
+        Lfo { rate: rate, phase: 0.0 }
+    }
+
+    // Sine from -1 to 1
+    pub fn next(&mut self, sample_rate: u32) -> f32 {
+        let val = (2.0 * PI * self.phase).sin();
+        self.phase = (self.phase + self.rate / sample_rate as f32) % 1.0;
+        val
+    }
+}
+
+// Turns a velocity into how much it is oscillating by removing the slow
+// drift and smoothing what is left.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WiggleDetector {
+    drift: f32,
+    level: f32,
+}
+
+impl WiggleDetector {
+    pub fn new() -> WiggleDetector {
+        WiggleDetector { drift: 0.0, level: 0.0 }
+    }
+
+    // Called once per frame with a velocity in mm/s, returns 0..1
+    pub fn update(&mut self, velocity: f32) -> f32 {
+        self.drift += (velocity - self.drift) * DRIFT_SMOOTHING;
+        let wiggle = (velocity - self.drift).abs();
+        self.level += (wiggle - self.level) * WIGGLE_SMOOTHING;
+        (self.level / FULL_WIGGLE_SPEED).min(1.0)
+    }
+}
+
+// Tremolo and vibrato owned by a single note
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoiceModulation {
+    settings: ModulationSettings,
+    tremolo: Lfo,
+    vibrato: Lfo,
+    detector: WiggleDetector,
+    depth: f32,
+}
+
+impl VoiceModulation {
+    pub fn new(settings: ModulationSettings) -> VoiceModulation {
+        VoiceModulation {
+            settings: settings,
+            tremolo: Lfo::new(settings.tremolo_rate),
+            vibrato: Lfo::new(settings.vibrato_rate),
+            detector: WiggleDetector::new(),
+            depth: if settings.depth_source == DepthSource::Constant { 1.0 } else { 0.0 },
+        }
+    }
+
+    pub fn update_controls(&mut self, hand: &LeapRustHand, finger: &LeapRustFinger) {
+        self.depth = match self.settings.depth_source {
+            DepthSource::Constant => 1.0,
+            DepthSource::Finger => self.detector.update(finger.tipVelocity.x),
+            DepthSource::Palm => self.detector.update(hand.palmVelocity.x),
+        };
+    }
+
+    // Returns the volume multiplier and the pitch ratio for the next sample
+    pub fn next(&mut self, sample_rate: u32) -> (f32, f32) {
+        let tremolo = self.tremolo.next(sample_rate);
+        let vibrato = self.vibrato.next(sample_rate);
+        let volume = 1.0 - self.settings.tremolo_depth * self.depth * 0.5 * (1.0 + tremolo);
+        let pitch = 2f32.powf(self.settings.vibrato_depth * self.depth * vibrato / 12.0);
+        (volume, pitch)
+    }
+}
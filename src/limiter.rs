@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+
+const LOOKAHEAD_SECONDS: f32 = 0.005;
+const RELEASE_SECONDS: f32 = 0.15;
+// level above which the limiter starts pulling the gain down
+const THRESHOLD: f32 = 0.9;
+const CEILING: f32 = 1.0;
+
+// Look-ahead peak limiter. The output is delayed by the look-ahead time so the
+// gain is already down by the time a peak arrives, and whatever still gets
+// through is rounded off by a soft clipper instead of hard clipping.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    delay: VecDeque<(f32, f32)>,
+    // sliding minimum of the gain each frame in the window needs
+    required: VecDeque<(u64, f32)>,
+    lookahead: usize,
+    frame: u64,
+    gain: f32,
+    attack: f32,
+    release: f32,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: u32) -> Limiter {
+        let lookahead = ((LOOKAHEAD_SECONDS * sample_rate as f32) as usize).max(1);
+        Limiter {
+            delay: VecDeque::with_capacity(lookahead + 1),
+            required: VecDeque::with_capacity(lookahead + 1),
+            lookahead: lookahead,
+            frame: 0,
+            gain: 1.0,
+            attack: 1.0 / lookahead as f32,
+            release: 1.0 - (-1.0 / (RELEASE_SECONDS * sample_rate as f32)).exp(),
+        }
+    }
+
+    pub fn process(&mut self, block: &mut [(f32, f32)]) {
+        for frame in block.iter_mut() {
+            let peak = frame.0.abs().max(frame.1.abs());
+            let required = if peak > THRESHOLD { THRESHOLD / peak } else { 1.0 };
+            while self.required.back().map_or(false, |x| x.1 >= required) {
+                self.required.pop_back();
+            }
+            self.required.push_back((self.frame, required));
+            while self.required.front().map_or(false, |x| x.0 + self.lookahead as u64 <= self.frame) {
+                self.required.pop_front();
+            }
+            let target = self.required.front().map_or(1.0, |x| x.1);
+            if target < self.gain {
+                self.gain = (self.gain - self.attack).max(target);
+            } else {
+                self.gain += (target - self.gain) * self.release;
+            }
+            self.frame += 1;
+
+            self.delay.push_back(*frame);
+            let delayed = if self.delay.len() > self.lookahead {
+                self.delay.pop_front().unwrap()
+            } else {
+                (0.0, 0.0)
+            };
+            *frame = (soft_clip(delayed.0 * self.gain), soft_clip(delayed.1 * self.gain));
+        }
+    }
+}
+
+// Leaves everything below the threshold alone and bends the rest smoothly
+// towards the ceiling.
+fn soft_clip(val: f32) -> f32 {
+    let magnitude = val.abs();
+    if magnitude <= THRESHOLD {
+        return val;
+    }
+    let headroom = CEILING - THRESHOLD;
+    let over = (magnitude - THRESHOLD) / headroom;
+    val.signum() * (THRESHOLD + headroom * over.tanh())
+}
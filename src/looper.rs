@@ -0,0 +1,211 @@
+use std::fmt::Display;
+
+use crate::preset::Preset;
+use crate::transport::TransportSettings;
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum LooperMode {
+    Off,
+    // first pass, sets down the loop
+    Recording,
+    Playing,
+    // playing back while recording another layer on top
+    Overdubbing,
+}
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum LooperCommand {
+    Record,
+    Overdub,
+    Play,
+    Stop,
+    // drops the newest layer
+    Undo,
+    Clear,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoopSettings {
+    pub bars: u32,
+}
+
+impl LoopSettings {
+    pub fn new() -> LoopSettings {
+        LoopSettings { bars: 4 }
+    }
+
+    pub fn next_bars(&self) -> u32 {
+        match self.bars {
+            1 => 2,
+            2 => 4,
+            4 => 8,
+            _ => 1,
+        }
+    }
+
+    // Loop length in samples, in bars of the transport's meter and tempo
+    pub fn length(&self, sample_rate: u32, transport: &TransportSettings) -> u64 {
+        let beats = (self.bars * transport.beats_per_bar) as f32;
+        (beats * 60.0 / transport.bpm.max(1.0) * sample_rate as f32) as u64
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} bars", self.bars)
+    }
+
+    pub fn from_preset(preset: &Preset) -> LoopSettings {
+        let defaults = LoopSettings::new();
+        LoopSettings {
+            bars: preset.get("looper.bars").unwrap_or(defaults.bars).max(1),
+        }
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset) {
+        preset.set("looper.bars", self.bars);
+    }
+}
+
+// Layers of events stamped with their sample position in the loop. Each
+// overdub pass goes into a layer of its own so it can be undone.
+#[derive(Debug, Clone)]
+pub struct Looper<E> {
+    pub settings: LoopSettings,
+    pub mode: LooperMode,
+    layers: Vec<Vec<(u64, E)>>,
+    // next event to play in each layer
+    cursors: Vec<usize>,
+    position: u64,
+    length: u64,
+}
+
+impl<E: Clone> Looper<E> {
+    pub fn new(settings: LoopSettings) -> Looper<E> {
+        Looper {
+            settings: settings,
+            mode: LooperMode::Off,
+            layers: Vec::new(),
+            cursors: Vec::new(),
+            position: 0,
+            length: 0,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.mode == LooperMode::Recording || self.mode == LooperMode::Overdubbing
+    }
+
+    // Whether the next tick brings the loop round to the start
+    pub fn at_end(&self) -> bool {
+        self.mode != LooperMode::Off && self.length > 0 && self.position + 1 >= self.length
+    }
+
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    fn new_layer(&mut self) {
+        self.layers.push(Vec::new());
+        self.cursors.push(0);
+    }
+
+    fn rewind(&mut self) {
+        self.position = 0;
+        for cursor in &mut self.cursors {
+            *cursor = 0;
+        }
+    }
+
+    pub fn command(&mut self, command: LooperCommand, sample_rate: u32, transport: &TransportSettings) {
+        match command {
+            LooperCommand::Record => {
+                self.layers.clear();
+                self.cursors.clear();
+                self.new_layer();
+                self.length = self.settings.length(sample_rate, transport);
+                self.rewind();
+                self.mode = LooperMode::Recording;
+            },
+            LooperCommand::Overdub => {
+                if self.mode == LooperMode::Playing {
+                    self.new_layer();
+                    self.mode = LooperMode::Overdubbing;
+                } else if self.mode == LooperMode::Overdubbing {
+                    self.mode = LooperMode::Playing;
+                }
+            },
+            LooperCommand::Play => {
+                if !self.layers.is_empty() {
+                    if self.mode == LooperMode::Off {
+                        self.rewind();
+                    }
+                    self.mode = LooperMode::Playing;
+                }
+            },
+            LooperCommand::Stop => {
+                self.mode = LooperMode::Off;
+            },
+            LooperCommand::Undo => {
+                if self.mode != LooperMode::Recording {
+                    self.layers.pop();
+                    self.cursors.pop();
+                    if self.layers.is_empty() {
+                        self.mode = LooperMode::Off;
+                    } else if self.mode == LooperMode::Overdubbing {
+                        self.mode = LooperMode::Playing;
+                    }
+                }
+            },
+            LooperCommand::Clear => {
+                self.layers.clear();
+                self.cursors.clear();
+                self.mode = LooperMode::Off;
+            },
+        }
+    }
+
+    // Keeps an event at the current position if recording
+    pub fn record(&mut self, event: E) {
+        if !self.is_recording() {
+            return;
+        }
+        if let Some(layer) = self.layers.last_mut() {
+            layer.push((self.position, event));
+        }
+    }
+
+    // Call once per sample. Collects the events due now with the layer they
+    // came from, and returns true when the loop has just come round.
+    pub fn tick(&mut self, events: &mut Vec<(usize, E)>) -> bool {
+        if self.mode == LooperMode::Off || self.length == 0 {
+            return false;
+        }
+        // the layer being recorded is heard live, not played back
+        let playing = match self.mode {
+            LooperMode::Overdubbing => self.layers.len() - 1,
+            LooperMode::Recording => 0,
+            _ => self.layers.len(),
+        };
+        for layer in 0..playing {
+            let cursor = &mut self.cursors[layer];
+            while let Some((at, event)) = self.layers[layer].get(*cursor) {
+                if *at > self.position {
+                    break;
+                }
+                events.push((layer, event.clone()));
+                *cursor += 1;
+            }
+        }
+        self.position += 1;
+        if self.position < self.length {
+            return false;
+        }
+        self.rewind();
+        match self.mode {
+            LooperMode::Recording => self.mode = LooperMode::Playing,
+            // every pass of an overdub is a layer of its own
+            LooperMode::Overdubbing => self.new_layer(),
+            _ => {},
+        }
+        true
+    }
+}
@@ -0,0 +1,171 @@
+use std::fs;
+use std::path::Path;
+
+// Shortest and longest pitch periods the autocorrelation will consider
+const MIN_PITCH: f32 = 40.0;
+const MAX_PITCH: f32 = 2000.0;
+const ENVELOPE_WINDOW_SECONDS: f32 = 0.01;
+const SUSTAIN_THRESHOLD: f32 = 0.5;
+const CROSSFADE_SECONDS: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoopPoints {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl LoopPoints {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplerChunk {
+    pub unity_note: u8,
+    pub loop_points: Option<LoopPoints>,
+}
+
+// Finds a sustain loop that starts on a rising zero crossing and spans a
+// whole number of pitch periods, so the waveform lines up across the seam.
+pub fn find_loop_points(signal: &[f32], sample_rate: u32) -> Option<LoopPoints> {
+    let (sustain_start, sustain_end) = find_sustain_region(signal, sample_rate)?;
+    let min_lag = (sample_rate as f32 / MAX_PITCH) as usize;
+    let max_lag = (sample_rate as f32 / MIN_PITCH) as usize;
+    let analysis_len = (max_lag * 4).min(sustain_end - sustain_start);
+    let analysis_start = sustain_start + (sustain_end - sustain_start - analysis_len) / 2;
+    let period = find_period(&signal[analysis_start..analysis_start + analysis_len], min_lag, max_lag)?;
+
+    let start = next_rising_zero_crossing(signal, sustain_start, sustain_start + period)?;
+    let periods = (sustain_end - start) / period;
+    if periods < 2 {
+        return None;
+    }
+    let guess = start + periods * period;
+    let end = nearest_rising_zero_crossing(signal, guess, period / 4).unwrap_or(guess);
+    if end <= start || end >= signal.len() {
+        return None;
+    }
+    Some(LoopPoints { start, end })
+}
+
+// Samples of crossfade to use before loop_end, limited by how much audio
+// precedes the loop start to fade in from.
+pub fn crossfade_length(loop_points: LoopPoints, sample_rate: u32) -> usize {
+    let wanted = (sample_rate as f32 * CROSSFADE_SECONDS) as usize;
+    wanted.min(loop_points.len() / 2).min(loop_points.start)
+}
+
+fn rms_envelope(signal: &[f32], window: usize) -> Vec<f32> {
+    signal.chunks(window)
+        .map(|chunk| (chunk.iter().map(|x| x * x).sum::<f32>() / chunk.len() as f32).sqrt())
+        .collect()
+}
+
+// The sustain region runs from the end of the attack (first window after the
+// envelope peak) until the envelope last drops below half of that peak.
+fn find_sustain_region(signal: &[f32], sample_rate: u32) -> Option<(usize, usize)> {
+    let window = ((sample_rate as f32 * ENVELOPE_WINDOW_SECONDS) as usize).max(1);
+    let envelope = rms_envelope(signal, window);
+    let (peak_index, peak) = envelope.iter().cloned().enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    if peak <= 0.0 {
+        return None;
+    }
+    let last_index = envelope.iter()
+        .rposition(|level| *level >= peak * SUSTAIN_THRESHOLD)?;
+    let start = (peak_index + 1) * window;
+    let end = (last_index * window).min(signal.len());
+    if end <= start {
+        return None;
+    }
+    Some((start, end))
+}
+
+// Normalized autocorrelation; the first peak close to the best one wins so
+// that we lock onto the fundamental rather than an octave below it.
+fn find_period(signal: &[f32], min_lag: usize, max_lag: usize) -> Option<usize> {
+    let max_lag = max_lag.min(signal.len() / 2);
+    if min_lag >= max_lag {
+        return None;
+    }
+    let correlations: Vec<f32> = (min_lag..max_lag)
+        .map(|lag| {
+            let (mut sum, mut energy_a, mut energy_b) = (0f32, 0f32, 0f32);
+            for i in 0..signal.len() - lag {
+                sum += signal[i] * signal[i + lag];
+                energy_a += signal[i] * signal[i];
+                energy_b += signal[i + lag] * signal[i + lag];
+            }
+            let energy = (energy_a * energy_b).sqrt();
+            if energy > 0.0 { sum / energy } else { 0.0 }
+        })
+        .collect();
+    let best = correlations.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    if best < 0.5 {
+        return None;
+    }
+    for i in 1..correlations.len().saturating_sub(1) {
+        let value = correlations[i];
+        if value >= best * 0.9 && value >= correlations[i - 1] && value >= correlations[i + 1] {
+            return Some(i + min_lag);
+        }
+    }
+    None
+}
+
+fn is_rising_zero_crossing(signal: &[f32], index: usize) -> bool {
+    index > 0 && index < signal.len() && signal[index - 1] < 0.0 && signal[index] >= 0.0
+}
+
+fn next_rising_zero_crossing(signal: &[f32], from: usize, to: usize) -> Option<usize> {
+    (from..to.min(signal.len())).find(|i| is_rising_zero_crossing(signal, *i))
+}
+
+fn nearest_rising_zero_crossing(signal: &[f32], around: usize, radius: usize) -> Option<usize> {
+    (0..=radius).find_map(|offset| {
+        if is_rising_zero_crossing(signal, around + offset) {
+            Some(around + offset)
+        } else if offset <= around && is_rising_zero_crossing(signal, around - offset) {
+            Some(around - offset)
+        } else {
+            None
+        }
+    })
+}
+
+// Reads the sampler chunk of a WAV file, giving the root note and the first
+// sustain loop if one was stored by the editor that produced the file.
+pub fn read_sampler_chunk(path: &Path) -> Option<SamplerChunk> {
+    let bytes = fs::read(path).ok()?;
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return None;
+    }
+    let read_u32 = |offset: usize| -> Option<u32> {
+        let slice = bytes.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([slice[0], slice[1], slice[2], slice[3]]))
+    };
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let id = &bytes[offset..offset + 4];
+        let size = read_u32(offset + 4)? as usize;
+        let data = offset + 8;
+        if id == b"smpl" {
+            let unity_note = read_u32(data + 12)?.min(127) as u8;
+            let loop_count = read_u32(data + 28)?;
+            let loop_points = if loop_count > 0 {
+                // the first loop record follows the 36 byte header; the end
+                // offset is inclusive
+                let start = read_u32(data + 36 + 8)? as usize;
+                let end = read_u32(data + 36 + 12)? as usize + 1;
+                if end > start { Some(LoopPoints { start, end }) } else { None }
+            } else {
+                None
+            };
+            return Some(SamplerChunk { unity_note, loop_points });
+        }
+        // chunks are padded to an even number of bytes
+        offset = data + size + (size & 1);
+    }
+    None
+}
@@ -1,17 +1,19 @@
 use cpal::{Sample, SampleFormat, StreamConfig, Stream};
 use cpal::traits::{DeviceTrait, HostTrait};
-use crate::leaprust::{LeapRustVector, LeapRustFrame};
+use crate::leaprust::{LeapRustFinger, LeapRustHand, LeapRustVector, LeapRustFrame};
 use crate::lrviz::AppEvent;
 use std::collections::HashMap;
 use std::f32::NEG_INFINITY;
-use std::f32::consts::PI;
+use std::f32::consts::{PI, SQRT_2};
 use std::fmt;
 use std::fmt::Display;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicPtr, Ordering};
-use rtrb::Consumer;
+use rtrb::{Consumer, Producer};
 use std::fs::File;
 use std::path::Path;
-use std::cmp::Ordering as CmpOrdering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use symphonia::core::io::MediaSourceStream;
 use symphonia::core::audio::SampleBuffer;
@@ -20,7 +22,26 @@ use symphonia::core::formats::FormatOptions;
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
 
+use crate::arp::{ArpEvent, ArpSettings, Arpeggiator};
+use crate::calibration::{Calibration, HandCalibrations};
+use crate::chord::ChordSettings;
+use crate::control::ControlSource;
+use crate::effects::{EffectsChain, EffectSettings};
+use crate::lfo::{DepthSource, ModulationSettings, VoiceModulation};
+use crate::looper::{LoopSettings, Looper, LooperCommand, LooperMode};
+use crate::limiter::Limiter;
+use crate::modmatrix::{ModMatrix, ModState, ModValues};
+use crate::filter::{EnvelopeSettings, FilterMode, FilterSettings, VoiceFilter};
+use crate::fm::{FmPatch, FmState};
+use crate::handview::{Finger, HandRole, HandSettings, HandView, Side};
+use crate::looping::{self, LoopPoints};
+use crate::midi::{self, ClockMessage, TimedMessage};
+use crate::multisample::{Multisample, DEFAULT_VELOCITY};
 use crate::notefreq;
+use crate::preset::Preset;
+use crate::transport::{Metronome, Transport, TransportCommand, TransportSettings, TransportTick};
+use crate::trigger::FingerTrigger;
+use crate::wavetable::Wavetable;
 
 #[derive(Debug, Clone, Copy, Display, PartialEq)]
 pub enum NoteState {
@@ -30,15 +51,6 @@ pub enum NoteState {
     Dead
 }
 
-#[derive(Debug, Clone, Copy, Display, PartialEq, Eq, Hash)]
-enum Finger {
-    Thumb,
-    Index,
-    Middle,
-    Ring,
-    Little
-}
-
 #[derive(Debug, Clone, Copy, Display, PartialEq)]
 pub enum NoteShape {
     Sine,
@@ -47,10 +59,23 @@ pub enum NoteShape {
     Triangle
 }
 
+impl NoteShape {
+    pub fn next(&self) -> NoteShape {
+        match self {
+            NoteShape::Sine => NoteShape::SineSquared,
+            NoteShape::SineSquared => NoteShape::Saw,
+            NoteShape::Saw => NoteShape::Triangle,
+            NoteShape::Triangle => NoteShape::Sine,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 struct PlaybackWave {
     freq: f32,
     target_freq: f32,
+    // vibrato on top of target_freq
+    bend: f32,
     shape: NoteShape,
     phase: f32,
 }
@@ -60,6 +85,7 @@ impl PlaybackWave {
         PlaybackWave {
             freq: freq,
             target_freq: freq,
+            bend: 1f32,
             shape: shape,
             phase: 0f32
         }
@@ -67,148 +93,259 @@ impl PlaybackWave {
 }
 
 #[derive(Debug, Clone, PartialEq)]
-struct PlaybackSample {
-    sample_def: Vec<f32>,
-    freq: u32,
-    target_freq: u32,
+pub struct PlaybackWavetable {
+    table: Arc<Wavetable>,
+    freq: f32,
+    target_freq: f32,
+    bend: f32,
     phase: f32,
-    first_sample: bool,
-    adjustment: u32,
-    loop_start: Option<usize>,
-    loop_end: Option<usize>
+    morph: f32,
 }
 
-
-fn yield_slices<'a>(v: &'a [f32]) -> Vec<&'a[f32]> {
-    let mut slices = vec![];
-    let mut start = 0;
-    let mut current_direction = CmpOrdering::Greater;
-    let sum_size = 10;
-    for i in sum_size..v.len() {
-        let direction = (v[i-(sum_size/2 - 1)..i].iter().sum::<f32>()).partial_cmp(&v[i-(sum_size - 1)..i-(sum_size/2)].iter().sum::<f32>()).unwrap();
-        if direction != current_direction {
-            slices.push(&v[start..i]);
-            start = i;
-            current_direction = direction;
+impl PlaybackWavetable {
+    fn new(table: Arc<Wavetable>, freq: f32) -> PlaybackWavetable {
+        PlaybackWavetable {
+            table: table,
+            freq: freq,
+            target_freq: freq,
+            bend: 1f32,
+            phase: 0f32,
+            morph: 0f32,
         }
     }
-    slices.push(&v[start..]);
-    slices
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackFm {
+    patch: FmPatch,
+    state: FmState,
+    freq: f32,
+    target_freq: f32,
+    bend: f32,
+    index_scale: f32,
+}
 
-fn find_sustain_sample_bounds(signal: &Vec<f32>) -> (Option<usize>, Option<usize>) {
-    let mut peak_amplitude = std::f32::NEG_INFINITY;
-    let mut peak_index = 0 as usize;
-    for (i, amplitude) in signal.iter().cloned().enumerate() {
-        if amplitude > peak_amplitude {
-            peak_index = i;
-            peak_amplitude = amplitude;
-        }
-    }
-    let slices = yield_slices(signal);
-    for slice in slices {
-        println!("slice len {} slice[0] {} slice[len-1] {}", slice.len(), slice[0], slice[slice.len() - 1]);
-    }
-    let threshold = peak_amplitude * 0.1;
-    let mut first_index = None;
-    let mut last_index = None;
-    for (i, amplitude) in signal[peak_index..].iter().cloned().enumerate() {
-        if amplitude >= peak_amplitude - threshold {
-            first_index = Some(i + peak_index);
-        } else {
-            break;
+impl PlaybackFm {
+    fn new(patch: FmPatch, freq: f32) -> PlaybackFm {
+        PlaybackFm {
+            patch: patch,
+            state: FmState::new(),
+            freq: freq,
+            target_freq: freq,
+            bend: 1f32,
+            index_scale: 1f32,
         }
     }
-    if let Some(first_index) = first_index {
-        for (i, amplitude) in signal[(first_index+1)..].iter().cloned().enumerate() {
-            if amplitude >= peak_amplitude - threshold {
-                last_index = Some(i + first_index);
-            }
+}
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum ReleaseMode {
+    // keep looping the sustain region while the note's volume decays
+    Loop,
+    // leave the loop on note-off and play out the recorded release
+    Tail,
+}
+
+impl FromStr for ReleaseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ReleaseMode, String> {
+        match s {
+            "Loop" => Ok(ReleaseMode::Loop),
+            "Tail" => Ok(ReleaseMode::Tail),
+            _ => Err(format!("unknown release mode {}", s)),
         }
     }
-    println!("peak index {} first_index {:?} last_index {:?} len {:?}", peak_index, first_index, last_index, signal.len());
-    ///panic!("ahhhhh");
-    (first_index, last_index)
+}
+
+// Trim and loop markers of a sample, in source sample frames. Samples
+// without a loop have neither loop marker.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplePoints {
+    pub start: usize,
+    pub loop_start: Option<usize>,
+    pub loop_end: Option<usize>,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackSample {
+    sample_def: Arc<Vec<f32>>,
+    freq: f32,
+    target_freq: f32,
+    bend: f32,
+    position: f64,
+    start: usize,
+    end: usize,
+    loop_start: Option<usize>,
+    loop_end: Option<usize>,
+    crossfade: usize,
+    release_mode: ReleaseMode,
+    released: bool,
+    // frequency of the note it plays, only known once repitched
+    pitch: f32,
 }
 
 
 impl PlaybackSample {
-    fn new(sample_def: Vec<f32>, freq: u32) -> PlaybackSample {
-        let (start, end) = find_sustain_sample_bounds(&sample_def);
+    fn new(sample_def: Vec<f32>, freq: f32, loop_points: Option<LoopPoints>) -> PlaybackSample {
+        let loop_points = loop_points.or_else(|| looping::find_loop_points(&sample_def, freq as u32));
+        let len = sample_def.len();
         PlaybackSample {
-            sample_def: sample_def,
+            sample_def: Arc::new(sample_def),
             target_freq: freq,
             freq: freq,
-            phase: 0f32,
-            adjustment: 0u32,
-            first_sample: true,
-            loop_start: start,
-            loop_end: end
+            bend: 1f32,
+            position: 0f64,
+            start: 0,
+            end: len,
+            loop_start: loop_points.map(|x| x.start),
+            loop_end: loop_points.map(|x| x.end),
+            crossfade: loop_points.map_or(0, |x| looping::crossfade_length(x, freq as u32)),
+            release_mode: ReleaseMode::Loop,
+            released: false,
+            pitch: 0.0,
+        }
+    }
+
+    // Plays the sample recorded at root_freq back at note_freq
+    pub fn repitched(&self, root_freq: f32, note_freq: f32) -> PlaybackSample {
+        let mut sample = self.clone();
+        sample.freq = self.freq * note_freq / root_freq;
+        sample.target_freq = sample.freq;
+        sample.pitch = note_freq;
+        sample
+    }
+
+    pub fn set_release_mode(&mut self, release_mode: ReleaseMode) {
+        self.release_mode = release_mode;
+    }
+
+    pub fn release_mode(&self) -> ReleaseMode {
+        self.release_mode
+    }
+
+    pub fn data(&self) -> &[f32] {
+        &self.sample_def
+    }
+
+    pub fn points(&self) -> SamplePoints {
+        SamplePoints {
+            start: self.start,
+            loop_start: self.loop_start,
+            loop_end: self.loop_end,
+            end: self.end,
         }
     }
+
+    pub fn set_points(&mut self, points: SamplePoints) {
+        let len = self.sample_def.len();
+        self.end = points.end.min(len);
+        self.start = points.start.min(self.end);
+        let loop_start = points.loop_start.map(|x| x.max(self.start));
+        let loop_end = points.loop_end.map(|x| x.min(self.end));
+        let loop_points = match (loop_start, loop_end) {
+            (Some(start), Some(end)) if start < end => Some(LoopPoints { start: start, end: end }),
+            _ => None,
+        };
+        if let Some(loop_points) = loop_points {
+            self.loop_start = Some(loop_points.start);
+            self.loop_end = Some(loop_points.end);
+            self.crossfade = looping::crossfade_length(loop_points, self.freq as u32);
+        } else {
+            self.loop_start = None;
+            self.loop_end = None;
+            self.crossfade = 0;
+        }
+        self.position = self.start as f64;
+    }
+
+    fn value_at(&self, position: f64) -> f32 {
+        let end = self.end;
+        let first_index = position as usize;
+        if first_index >= end {
+            return 0f32;
+        }
+        let second_index = (first_index + 1).min(end - 1);
+        let second_weight = (position - position.floor()) as f32;
+        self.sample_def[first_index] * (1.0 - second_weight) + self.sample_def[second_index] * second_weight
+    }
 }
 
 trait PlaybackTypeItem {
     fn get_sample(&mut self, sample_rate: u32, i: u32) -> f32;
-    fn adjust_freq(&mut self, mult: f32);
+    // Pitch ratio applied on top of the played frequency, not cumulative
+    fn set_bend(&mut self, ratio: f32);
+    // Moves the played frequency itself, before any bend
+    fn transpose(&mut self, ratio: f32);
+    // Frequency of the note played, before any bend
+    fn pitch(&self) -> f32;
+
+    fn release(&mut self) {}
+
+    // Whether the item fades out by itself once released
+    fn has_release_tail(&self) -> bool {
+        false
+    }
+
+    fn is_finished(&self) -> bool {
+        false
+    }
+
+    // Moves the item through its timbre range, 0..1
+    fn set_timbre(&mut self, _timbre: f32) {}
 }
 
 
 impl PlaybackTypeItem for PlaybackSample {
-    fn get_sample(&mut self, sample_rate: u32, i: u32) -> f32 {
-        // if self.target_freq != self.freq || self.first_sample{
-        //     let old_index = if self.first_sample {
-        //         println!("self.first_sample");
-        //         self.first_sample = false;
-        //         0f32
-        //     } else {
-        //         (i as f32 * (self.freq as f32 / sample_rate as f32) as f32) + self.phase
-        //     };
-        //     let new_index = i as f32 * (self.target_freq as f32 / sample_rate as f32) as f32;
-        //     self.freq = self.target_freq;
-        //     self.phase = old_index - new_index;
-        // }
-        let mut sample_index = ((i - self.adjustment) as f32 * (self.freq as f32 / sample_rate as f32) as f32) + self.phase;
-        let mut show_debug = false;
-        if let (Some(loop_start), Some(loop_end)) = (self.loop_start, self.loop_end) {
-            if sample_index > loop_end as f32 {
-                println!("current index {} loop_start {} loop_end {} phase {}", sample_index, loop_start, loop_end, self.phase);
-                let adjustment_incr = (loop_end as u32 - loop_start as u32) as f32 *  sample_rate as f32 / self.freq as f32;
-                self.adjustment += adjustment_incr as u32;
-                let new_index = ((i - self.adjustment) as f32 * (self.freq as f32 / sample_rate as f32) as f32) + self.phase;
-                sample_index = new_index;
-                show_debug = true;
+    fn get_sample(&mut self, sample_rate: u32, _i: u32) -> f32 {
+        self.freq = self.target_freq * self.bend;
+        let mut result = self.value_at(self.position);
+        let looping = !self.released;
+        if let (true, Some(loop_start), Some(loop_end)) = (looping, self.loop_start, self.loop_end) {
+            let loop_len = (loop_end - loop_start) as f64;
+            let fade_start = (loop_end - self.crossfade) as f64;
+            // fade the end of the loop into the audio leading up to loop_start
+            // so the jump back lands on matching material
+            if self.crossfade > 0 && self.position >= fade_start {
+                let weight = ((self.position - fade_start) / self.crossfade as f64) as f32;
+                result = result * (1.0 - weight) + self.value_at(self.position - loop_len) * weight;
+            }
+            self.position += (self.freq / sample_rate as f32) as f64;
+            if self.position >= loop_end as f64 {
+                self.position -= loop_len;
             }
+        } else {
+            self.position += (self.freq / sample_rate as f32) as f64;
         }
+        result
+    }
 
-        let first_index = (sample_index as u32 % self.sample_def.len() as u32) as usize;
-        let second_index = ((sample_index as u32 + 1) % self.sample_def.len() as u32) as usize;
-        let second_weight = (sample_index - sample_index.floor());
-        let first_weight = 1.0 - second_weight;
-
-        let raw_sample_value =
-            self.sample_def[first_index] as f32 * first_weight +
-            self.sample_def[second_index] as f32 * second_weight
-        ;
-        let result = raw_sample_value as f32 * 10.0;
-        if i % 81049 == 0 || show_debug {
-            println!(
-                "i: {}, Freq: {}, Sample rate: {}, Sample index: {}, data len: {}, Raw Val: {}, result: {}",
-                i,
-                self.freq,
-                sample_rate,
-                sample_index,
-                self.sample_def.len(),
-                raw_sample_value,
-                result
-            );
+    fn set_bend(&mut self, ratio: f32) {
+        self.bend = ratio;
+    }
+
+    fn transpose(&mut self, ratio: f32) {
+        self.target_freq *= ratio;
+        self.pitch *= ratio;
+    }
+
+    fn pitch(&self) -> f32 {
+        self.pitch
+    }
+
+    fn release(&mut self) {
+        if self.release_mode == ReleaseMode::Tail {
+            self.released = true;
         }
-        result
     }
 
-    fn adjust_freq(&mut self, mult: f32) {
-        self.target_freq = (self.target_freq as f32 * mult).round() as u32;
+    fn has_release_tail(&self) -> bool {
+        self.release_mode == ReleaseMode::Tail
+    }
+
+    fn is_finished(&self) -> bool {
+        self.position >= self.end as f64
     }
 }
 
@@ -216,9 +353,10 @@ impl PlaybackTypeItem for PlaybackWave {
     fn get_sample(&mut self, sample_rate: u32, i: u32) -> f32 {
         let twopi = 2.0 * PI;
         let t = (i as f32 % (sample_rate as f32 * self.freq)) as f32 / sample_rate as f32;
-        if self.target_freq != self.freq {
-            self.phase = (self.phase + 2.0 * PI * (t * self.freq - self.target_freq * t)) % twopi;
-            self.freq = self.target_freq;
+        let freq = self.target_freq * self.bend;
+        if freq != self.freq {
+            self.phase = (self.phase + 2.0 * PI * (t * self.freq - freq * t)) % twopi;
+            self.freq = freq;
         }
         let position = (2.0 * PI * t * self.freq) + self.phase;
         let val = position.sin();
@@ -240,29 +378,152 @@ impl PlaybackTypeItem for PlaybackWave {
         }
     }
 
-    fn adjust_freq(&mut self, mult: f32) {
-        self.target_freq = self.target_freq * mult;
+    fn set_bend(&mut self, ratio: f32) {
+        self.bend = ratio;
+    }
+
+    fn transpose(&mut self, ratio: f32) {
+        self.target_freq *= ratio;
+    }
+
+    fn pitch(&self) -> f32 {
+        self.target_freq
+    }
+}
+
+impl PlaybackTypeItem for PlaybackWavetable {
+    fn get_sample(&mut self, sample_rate: u32, _i: u32) -> f32 {
+        self.freq = self.target_freq * self.bend;
+        let val = self.table.sample(self.phase, self.morph, self.freq, sample_rate);
+        self.phase = (self.phase + self.freq / sample_rate as f32) % 1.0;
+        val
+    }
+
+    fn set_bend(&mut self, ratio: f32) {
+        self.bend = ratio;
+    }
+
+    fn transpose(&mut self, ratio: f32) {
+        self.target_freq *= ratio;
+    }
+
+    fn pitch(&self) -> f32 {
+        self.target_freq
+    }
+
+    fn set_timbre(&mut self, timbre: f32) {
+        self.morph = timbre;
+    }
+}
+
+impl PlaybackTypeItem for PlaybackFm {
+    fn get_sample(&mut self, sample_rate: u32, _i: u32) -> f32 {
+        self.freq = self.target_freq * self.bend;
+        self.state.sample(&self.patch, self.freq, sample_rate, self.index_scale)
+    }
+
+    fn set_bend(&mut self, ratio: f32) {
+        self.bend = ratio;
+    }
+
+    fn transpose(&mut self, ratio: f32) {
+        self.target_freq *= ratio;
+    }
+
+    fn pitch(&self) -> f32 {
+        self.target_freq
+    }
+
+    fn set_timbre(&mut self, timbre: f32) {
+        self.index_scale = timbre;
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 enum PlaybackType {
     Wave(PlaybackWave),
-    Sample(PlaybackSample)
+    Sample(PlaybackSample),
+    Wavetable(PlaybackWavetable),
+    Fm(PlaybackFm),
 }
 
 impl PlaybackType {
+    // Picks the closest zone of the instrument and repitches it to freq
+    fn sample(instrument: &Multisample, freq: f32) -> PlaybackType {
+        PlaybackType::Sample(
+            instrument.sample_for(freq, DEFAULT_VELOCITY).expect("instrument has no zones")
+        )
+    }
+
     fn get_sample(&mut self, sample_rate: u32, i: u32) -> f32 {
         match self {
             PlaybackType::Wave(x) => x.get_sample(sample_rate, i),
             PlaybackType::Sample(x) => x.get_sample(sample_rate, i),
+            PlaybackType::Wavetable(x) => x.get_sample(sample_rate, i),
+            PlaybackType::Fm(x) => x.get_sample(sample_rate, i),
         }
     }
 
-    fn adjust_freq(&mut self, mult: f32) {
+    fn set_bend(&mut self, ratio: f32) {
         match self {
-            PlaybackType::Wave(x) => x.adjust_freq(mult),
-            PlaybackType::Sample(x) => x.adjust_freq(mult),
+            PlaybackType::Wave(x) => x.set_bend(ratio),
+            PlaybackType::Sample(x) => x.set_bend(ratio),
+            PlaybackType::Wavetable(x) => x.set_bend(ratio),
+            PlaybackType::Fm(x) => x.set_bend(ratio),
+        }
+    }
+
+    fn transpose(&mut self, ratio: f32) {
+        match self {
+            PlaybackType::Wave(x) => x.transpose(ratio),
+            PlaybackType::Sample(x) => x.transpose(ratio),
+            PlaybackType::Wavetable(x) => x.transpose(ratio),
+            PlaybackType::Fm(x) => x.transpose(ratio),
+        }
+    }
+
+    fn pitch(&self) -> f32 {
+        match self {
+            PlaybackType::Wave(x) => x.pitch(),
+            PlaybackType::Sample(x) => x.pitch(),
+            PlaybackType::Wavetable(x) => x.pitch(),
+            PlaybackType::Fm(x) => x.pitch(),
+        }
+    }
+
+    fn release(&mut self) {
+        match self {
+            PlaybackType::Wave(x) => x.release(),
+            PlaybackType::Sample(x) => x.release(),
+            PlaybackType::Wavetable(x) => x.release(),
+            PlaybackType::Fm(x) => x.release(),
+        }
+    }
+
+    fn has_release_tail(&self) -> bool {
+        match self {
+            PlaybackType::Wave(x) => x.has_release_tail(),
+            PlaybackType::Sample(x) => x.has_release_tail(),
+            PlaybackType::Wavetable(x) => x.has_release_tail(),
+            PlaybackType::Fm(x) => x.has_release_tail(),
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        match self {
+            PlaybackType::Wave(x) => x.is_finished(),
+            PlaybackType::Sample(x) => x.is_finished(),
+            PlaybackType::Wavetable(x) => x.is_finished(),
+            PlaybackType::Fm(x) => x.is_finished(),
+        }
+    }
+
+    fn set_timbre(&mut self, timbre: f32) {
+        match self {
+            PlaybackType::Wave(x) => x.set_timbre(timbre),
+            PlaybackType::Sample(x) => x.set_timbre(timbre),
+            PlaybackType::Wavetable(x) => x.set_timbre(timbre),
+            PlaybackType::Fm(x) => x.set_timbre(timbre),
         }
     }
 }
@@ -271,19 +532,140 @@ impl PlaybackType {
 #[derive(Debug, Clone)]
 pub struct TriggerDefinition {
     notes: Vec<PlaybackType>,
+    gain: f32,
+    timbre: ControlSource,
+    filter: Option<FilterSettings>,
+    modulation: Option<ModulationSettings>,
+    // note played from the instrument, so the zone can follow velocity
+    layered_note: Option<f32>,
 }
 
 impl TriggerDefinition {
+    fn new(notes: Vec<PlaybackType>) -> TriggerDefinition {
+        TriggerDefinition {
+            notes: notes,
+            gain: 1.0,
+            timbre: ControlSource::Off,
+            filter: None,
+            modulation: None,
+            layered_note: None,
+        }
+    }
+
+    fn with_gain(mut self, gain: f32) -> TriggerDefinition {
+        self.gain = gain;
+        self
+    }
+
+    fn with_filter(mut self, filter: FilterSettings) -> TriggerDefinition {
+        self.filter = Some(filter);
+        self
+    }
+
+    fn with_timbre(mut self, timbre: ControlSource) -> TriggerDefinition {
+        self.timbre = timbre;
+        self
+    }
+
+    fn with_modulation(mut self, modulation: ModulationSettings) -> TriggerDefinition {
+        self.modulation = Some(modulation);
+        self
+    }
+
+    fn set_timbre(&mut self, timbre: f32) {
+        for note in &mut self.notes {
+            note.set_timbre(timbre);
+        }
+    }
+
+    fn with_velocity_layers(mut self, freq: f32) -> TriggerDefinition {
+        self.layered_note = Some(freq);
+        self
+    }
+
+    // Swaps in the instrument's zone for this velocity
+    fn pick_layer(&mut self, instrument: &Multisample, velocity: u8) {
+        let sample = self.layered_note.and_then(|freq| instrument.sample_for(freq, velocity));
+        if let Some(sample) = sample {
+            self.notes = vec!(PlaybackType::Sample(sample));
+        }
+    }
+
+    fn set_bend(&mut self, ratio: f32) {
+        for note in &mut self.notes {
+            note.set_bend(ratio);
+        }
+    }
+
+    fn transpose(&mut self, ratio: f32) {
+        for note in &mut self.notes {
+            note.transpose(ratio);
+        }
+    }
+
+    // The lowest note, which is the root of a chord
+    fn pitch(&self) -> f32 {
+        self.notes.iter().map(|x| x.pitch()).fold(f32::INFINITY, f32::min)
+    }
+
     fn get_sample(&mut self, sample_rate: u32, i: u32) -> f32 {
         let mut sum = 0f32;
         for note in &mut self.notes {
             sum += note.get_sample(sample_rate, i);
         }
-        sum
+        sum * self.gain
+    }
+
+    fn release(&mut self) {
+        for note in &mut self.notes {
+            note.release();
+        }
+    }
+
+    // Only let the playback end the note when every part of it has a tail
+    fn has_release_tail(&self) -> bool {
+        self.notes.len() > 0 && self.notes.iter().all(|x| x.has_release_tail())
+    }
+
+    fn is_finished(&self) -> bool {
+        self.notes.iter().all(|x| x.is_finished())
     }
 }
 
 pub struct State {
+    violin: Multisample,
+    effects: EffectsChain,
+    limiter: Limiter,
+    master_gain: f32,
+    matrix: ModMatrix,
+    calibration: HandCalibrations,
+    triggers: HashMap<NoteKey, FingerTrigger>,
+    hands: HandSettings,
+    chords: ChordSettings,
+    // octaves everything plays shifted by
+    octave: i32,
+    // sustain pedal from the keyboard, a gesture latch or a closed fist
+    sustain_key: bool,
+    sustain_latched: bool,
+    sustain_fist: bool,
+    arp: Arpeggiator<NoteKey>,
+    // the note each finger held for the arpeggiator would play
+    arp_held: HashMap<NoteKey, Note>,
+    looper: Looper<LoopEvent>,
+    // events the looper plays this sample, kept to save allocating
+    loop_events: Vec<(usize, LoopEvent)>,
+    // waits for the next downbeat while the transport runs
+    queued_loop: Option<LooperCommand>,
+    transport: Transport,
+    metronome: Metronome,
+    // metronome clicks for this block, mixed in after the effects
+    clicks: Vec<f32>,
+    // MIDI out goes to its own thread, timed by when each sample is heard
+    clock_out: Option<Producer<TimedMessage>>,
+    block_heard_at: Instant,
+    block_position: u32,
+    // effect sends follow the playing hand rather than a single note
+    send_state: ModState,
     selected_map: i32,
     freq_map: HashMap<i32, HashMap<Finger, TriggerDefinition>>,
     active_playback: Vec<Note>,
@@ -293,45 +675,94 @@ pub struct State {
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NoteSource {
+    Live,
+    Audition,
+    // played by the arpeggiator from a held finger
+    Arp,
+    // played back from a layer of the looper
+    Loop(usize),
+}
+
+// What the looper keeps of a performance
+#[derive(Debug, Clone)]
+enum LoopEvent {
+    NoteOn(Note),
+    NoteOff(NoteKey),
+    Controls(NoteKey, ModValues),
+}
+
+// Which finger of which hand a voice belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct NoteKey {
+    side: Side,
+    finger: Finger,
+}
+
 #[derive(Debug, Clone)]
 struct Note {
-    finger: Finger,
+    key: NoteKey,
+    source: NoteSource,
+    // let go while the sustain pedal was down, still sounding
+    sustained: bool,
     state: NoteState,
     volume: f32,
     target_volume: f32,
-    position: LeapRustVector,
-    phase: f32,
+    // volume added per sample while rising
+    attack: f32,
 
     trigger: TriggerDefinition,
+    filter: Option<VoiceFilter>,
+    modulation: Option<VoiceModulation>,
+    mod_state: ModState,
+    mods: ModValues,
+    // pitch ratio from the modulation matrix
+    bend: f32,
 }
 
 
 impl Note {
     fn kill(&mut self) {
         self.state = NoteState::Dying;
+        self.trigger.release();
+        if let Some(filter) = &mut self.filter {
+            filter.release();
+        }
     }
 
     fn should_retain(&self) -> bool {
         return self.state != NoteState::Dead
     }
 
-    fn matches(&self, finger: Finger) -> bool {
-        return self.finger == finger && self.state != NoteState::Dying && self.state != NoteState::Dead
+    // Whether the finger's hand movement steers this note
+    fn follows(&self, key: NoteKey) -> bool {
+        let played = self.source == NoteSource::Live || self.source == NoteSource::Arp;
+        return played && self.key == key && !self.sustained && self.state != NoteState::Dying && self.state != NoteState::Dead
+    }
+
+    fn matches(&self, key: NoteKey) -> bool {
+        return self.source == NoteSource::Live && self.key == key && !self.sustained && self.state != NoteState::Dying && self.state != NoteState::Dead
     }
 
-    fn getSample(self: &mut Self, sample_rate: u32, i: u32) -> f32 {
+    fn getSample(self: &mut Self, sample_rate: u32, i: u32) -> (f32, f32) {
         if self.state == NoteState::Dead {
-            return 0f32;
+            return (0f32, 0f32);
         }
 
         if self.state == NoteState::Rising {
-            self.volume += 0.000002;
+            self.volume += self.attack;
             if self.volume > self.target_volume {
                 self.state = NoteState::Steady;
             }
         }
 
-        if self.state == NoteState::Dying {
+        if self.state == NoteState::Dying && self.trigger.has_release_tail() {
+            if self.trigger.is_finished() {
+                self.volume = 0f32;
+                self.state = NoteState::Dead;
+            }
+        } else if self.state == NoteState::Dying {
             self.volume = self.volume * 0.99995 - 0.00000001;
             if self.volume < 0f32 {
                 self.volume = 0f32;
@@ -339,128 +770,311 @@ impl Note {
             }
         }
 
-        self.trigger.get_sample(sample_rate, i) * self.volume
+        let mut tremolo = 1f32;
+        if let Some(modulation) = &mut self.modulation {
+            let (volume, bend) = modulation.next(sample_rate);
+            tremolo = volume;
+            self.trigger.set_bend(self.bend * bend);
+        }
+        let mut val = self.trigger.get_sample(sample_rate, i);
+        if let Some(filter) = &mut self.filter {
+            val = filter.process(val, sample_rate);
+        }
+        let val = val * self.volume * tremolo * self.mods.volume;
+        // equal power, scaled so a centred note keeps its level on both sides
+        let angle = (self.mods.pan + 1.0) * PI / 4.0;
+        (val * angle.cos() * SQRT_2, val * angle.sin() * SQRT_2)
     }
 
-    fn update_position(&mut self, position: LeapRustVector) {
-        if position.x != self.position.x {
-            let delta = (position.x - self.position.x) / 1000.0;
-            let multiplier = (1.0 + delta);
-            for wave in &mut self.trigger.notes {
-                wave.adjust_freq(multiplier);
-            }
-            self.position.x = position.x;
+    // Replays modulation recorded from another note
+    fn apply_mods(&mut self, mods: ModValues) {
+        self.mods = mods;
+        self.bend = 2f32.powf(mods.pitch / 12.0);
+        if self.modulation.is_none() {
+            self.trigger.set_bend(self.bend);
+        }
+        if let Some(timbre) = mods.timbre {
+            self.trigger.set_timbre(timbre.max(0.0).min(1.0));
+        }
+        if let Some(filter) = &mut self.filter {
+            filter.set_cutoff_modulation(mods.cutoff);
         }
     }
+
+    // Follows the hand through the trigger's own controls and the
+    // modulation matrix
+    fn update_controls(&mut self, hand: &LeapRustHand, finger: &LeapRustFinger, matrix: &ModMatrix, calibration: &Calibration) {
+        self.mods = self.mod_state.update(matrix, hand, finger, calibration);
+        self.bend = 2f32.powf(self.mods.pitch / 12.0);
+        if self.modulation.is_none() {
+            self.trigger.set_bend(self.bend);
+        }
+        let timbre = match (self.trigger.timbre.read(hand, finger, calibration), self.mods.timbre) {
+            (Some(timbre), Some(offset)) => Some(timbre + offset),
+            (timbre, offset) => timbre.or(offset),
+        };
+        if let Some(timbre) = timbre {
+            self.trigger.set_timbre(timbre.max(0.0).min(1.0));
+        }
+        if let Some(filter) = &mut self.filter {
+            filter.update_controls(hand, finger, calibration);
+            filter.set_cutoff_modulation(self.mods.cutoff);
+        }
+        if let Some(modulation) = &mut self.modulation {
+            modulation.update_controls(hand, finger);
+        }
+    }
+}
+
+// Decodes an audio file to mono, returning the data and its sample rate
+pub fn decode_file(path: &str) -> (Vec<f32>, u32) {
+    try_decode_file(path).unwrap()
 }
 
-fn file_to_sample(path: &str) -> PlaybackSample {
-    let file = Box::new(File::open(Path::new(path)).expect(&format!("Couldn't open path {}", path)));
+// Mono data and sample rate of an audio file
+pub fn try_decode_file(path: &str) -> Result<(Vec<f32>, u32), String> {
+    let path = Path::new(path);
+    let file = Box::new(File::open(path).map_err(|err| format!("Couldn't open path {}: {}", path.display(), err))?);
     let mss = MediaSourceStream::new(file, Default::default());
     let mut hint = Hint::new();
-    hint.with_extension("mp3");
+    if let Some(extension) = path.extension().and_then(|x| x.to_str()) {
+        hint.with_extension(extension);
+    }
     let format_opts: FormatOptions = Default::default();
     let metadata_opts: MetadataOptions = Default::default();
     let decoder_opts: DecoderOptions = Default::default();
-    let probed = symphonia::default::get_probe().format(&hint, mss, &format_opts, &metadata_opts).expect("unsupported format");
+    let probed = symphonia::default::get_probe().format(&hint, mss, &format_opts, &metadata_opts)
+        .map_err(|err| format!("unsupported format in {}: {}", path.display(), err))?;
     let mut format = probed.format;
-    let track = format.default_track().unwrap();
+    let track = format.default_track().ok_or(format!("no audio track in {}", path.display()))?;
     let mut decoder = symphonia::default::get_codecs().make(
         &track.codec_params,
         &decoder_opts
-    ).unwrap();
+    ).map_err(|err| format!("unsupported codec in {}: {}", path.display(), err))?;
     let mut joined_data = Vec::new();
     let mut spec_freq = 0;
     while let Some(packet) = format.next_packet().ok() {
-        let decoded = decoder.decode(&packet).expect("Unable to decode packet");
+        let decoded = decoder.decode(&packet).map_err(|err| format!("Unable to decode packet: {}", err))?;
         let spec = *decoded.spec();
         spec_freq = spec.rate;
         let mut samples = SampleBuffer::new(decoded.frames() as u64, spec);
         samples.copy_interleaved_ref(decoded);
-        for frame in samples.samples().chunks(spec.channels.count()) {
-            for (chan, sample) in frame.iter().enumerate() {
-                joined_data.push(*sample);
-            }
+        // mix down to mono so indexes line up with frames
+        let channels = spec.channels.count();
+        for frame in samples.samples().chunks(channels) {
+            joined_data.push(frame.iter().sum::<f32>() / channels as f32);
         }
     }
 
+    Ok((joined_data, spec_freq))
+}
+
+// Scales the data so its loudest sample sits at full scale
+fn normalize(data: &mut [f32]) {
+    let peak = data.iter().cloned().map(f32::abs).fold(0f32, f32::max);
+    if peak > 0.0 {
+        for val in data.iter_mut() {
+            *val /= peak;
+        }
+    }
+}
+
+pub fn file_to_sample(path: &str) -> PlaybackSample {
+    let (mut joined_data, spec_freq) = decode_file(path);
+    normalize(&mut joined_data);
+    let loop_points = looping::read_sampler_chunk(Path::new(path)).and_then(|x| x.loop_points);
     let sample = PlaybackSample::new(
         joined_data,
-        spec_freq
+        spec_freq as f32,
+        loop_points
     );
     sample
 }
 
 
+const VIOLIN_FILES: [&str; 10] = [
+    "/home/drew/Downloads/Strings/violin/violin_A4_1_piano_arco-normal.mp3",
+    "/home/drew/Downloads/Strings/violin/violin_A4_1_fortissimo_arco-normal.mp3",
+    "/home/drew/Downloads/Strings/violin/violin_B4_1_piano_arco-normal.mp3",
+    "/home/drew/Downloads/Strings/violin/violin_B4_1_fortissimo_arco-normal.mp3",
+    "/home/drew/Downloads/Strings/violin/violin_Cs5_1_piano_arco-normal.mp3",
+    "/home/drew/Downloads/Strings/violin/violin_Cs5_1_fortissimo_arco-normal.mp3",
+    "/home/drew/Downloads/Strings/violin/violin_E5_1_piano_arco-normal.mp3",
+    "/home/drew/Downloads/Strings/violin/violin_E5_1_fortissimo_arco-normal.mp3",
+    "/home/drew/Downloads/Strings/violin/violin_Fs5_1_piano_arco-normal.mp3",
+    "/home/drew/Downloads/Strings/violin/violin_Fs5_1_fortissimo_arco-normal.mp3",
+];
+
+// Soft and loud takes of each note, so how hard a finger presses picks the
+// layer. violin.files swaps in other recordings, separated by spaces.
+pub fn load_violin(preset: &Preset) -> Multisample {
+    let files: Option<String> = preset.get("violin.files");
+    let files: Vec<&str> = match &files {
+        Some(files) => files.split_whitespace().collect(),
+        None => VIOLIN_FILES.to_vec(),
+    };
+    let mut violin = Multisample::from_files(&files);
+    // the recordings have their own release, zones can loop instead in the preset
+    violin.set_release_mode(ReleaseMode::Tail);
+    violin.apply_preset(preset, "violin");
+    violin
+}
+
+// Each finger presses at its hand's calibrated height and has to lift a
+// little higher to let go
+fn finger_triggers(calibrations: &HandCalibrations) -> HashMap<NoteKey, FingerTrigger> {
+    let mut triggers = HashMap::new();
+    for side in [Side::Left, Side::Right] {
+        for finger in Finger::ALL {
+            let key = NoteKey { side: side, finger: finger };
+            triggers.insert(key, FingerTrigger::new(calibrations.hand(side).trigger_settings(finger.index())));
+        }
+    }
+    triggers
+}
+
+// The map whose fingers play a chord on each degree of the scale
+const CHORD_MAP: i32 = 1;
+// pinch strength that turns a chord into a seventh
+const SEVENTH_PINCH: f32 = 0.7;
+
+fn chord_trigger(chords: &ChordSettings, finger: Finger, seventh: bool) -> TriggerDefinition {
+    let notes = chords.chord(finger.index(), seventh).into_iter()
+        .map(|freq| PlaybackType::Wave(PlaybackWave::new(freq, NoteShape::SineSquared)))
+        .collect::<Vec<PlaybackType>>();
+    // a triad's notes play as loud as the single notes of the other maps;
+    // a seventh shares the triad's total so pinching it in doesn't jump the
+    // level into the limiter
+    let gain = 3.0 / notes.len() as f32;
+    TriggerDefinition::new(notes).with_gain(gain)
+}
+
+fn chord_map(chords: &ChordSettings) -> HashMap<Finger, TriggerDefinition> {
+    let mut map = HashMap::new();
+    for finger in Finger::ALL {
+        map.insert(finger, chord_trigger(chords, finger, false));
+    }
+    map
+}
+
+fn sample_map(instrument: &Multisample) -> HashMap<Finger, TriggerDefinition> {
+    // wiggling a fingertip plays vibrato like on a string
+    let vibrato = ModulationSettings::vibrato(5.5, 0.5, DepthSource::Finger);
+    let mut sample_map = HashMap::new();
+    sample_map.insert(Finger::Thumb, TriggerDefinition::new(vec!(
+        PlaybackType::sample(instrument, notefreq::A_4)
+    )).with_modulation(vibrato).with_velocity_layers(notefreq::A_4));
+    sample_map.insert(Finger::Index, TriggerDefinition::new(vec!(
+        PlaybackType::sample(instrument, notefreq::B_4)
+    )).with_modulation(vibrato).with_velocity_layers(notefreq::B_4));
+    sample_map.insert(Finger::Middle, TriggerDefinition::new(vec!(
+        PlaybackType::sample(instrument, notefreq::Cs5)
+    )).with_modulation(vibrato).with_velocity_layers(notefreq::Cs5));
+    sample_map.insert(Finger::Ring, TriggerDefinition::new(vec!(
+        PlaybackType::sample(instrument, notefreq::E_5)
+    )).with_modulation(vibrato).with_velocity_layers(notefreq::E_5));
+    sample_map.insert(Finger::Little, TriggerDefinition::new(vec!(
+        PlaybackType::sample(instrument, notefreq::Fs5)
+    )).with_modulation(vibrato).with_velocity_layers(notefreq::Fs5));
+    sample_map
+}
+
 impl State {
-    fn new(sample_rate: u32) -> State {
+    fn new(
+        sample_rate: u32,
+        violin: Multisample,
+        wavetable: Wavetable,
+        effects: EffectSettings,
+        master_gain: f32,
+        matrix: ModMatrix,
+        calibration: HandCalibrations,
+        hands: HandSettings,
+        chords: ChordSettings,
+        arp: ArpSettings,
+        looper: LoopSettings,
+        transport: TransportSettings,
+        clock_out: Option<Producer<TimedMessage>>
+    ) -> State {
         let mut map: HashMap<i32, HashMap<Finger, TriggerDefinition>> = HashMap::new();
+        // shaking the hand adds tremolo
+        let tremolo = ModulationSettings::tremolo(6.0, 0.8, DepthSource::Palm);
         let mut default_map = HashMap::new();
-        default_map.insert(Finger::Thumb, TriggerDefinition{notes: vec!(
+        default_map.insert(Finger::Thumb, TriggerDefinition::new(vec!(
             PlaybackType::Wave(PlaybackWave::new(notefreq::C_4, NoteShape::SineSquared))
-        )});
-        default_map.insert(Finger::Index, TriggerDefinition{notes: vec!(
+        )).with_modulation(tremolo));
+        default_map.insert(Finger::Index, TriggerDefinition::new(vec!(
             PlaybackType::Wave(PlaybackWave::new(notefreq::D_4, NoteShape::SineSquared))
-        )});
-        default_map.insert(Finger::Middle, TriggerDefinition{notes: vec!(
+        )).with_modulation(tremolo));
+        default_map.insert(Finger::Middle, TriggerDefinition::new(vec!(
             PlaybackType::Wave(PlaybackWave::new(notefreq::E_4, NoteShape::SineSquared))
-        )});
-        default_map.insert(Finger::Ring, TriggerDefinition{notes: vec!(
+        )).with_modulation(tremolo));
+        default_map.insert(Finger::Ring, TriggerDefinition::new(vec!(
             PlaybackType::Wave(PlaybackWave::new(notefreq::F_4, NoteShape::SineSquared))
-        )});
-        default_map.insert(Finger::Little, TriggerDefinition{notes: vec!(
+        )).with_modulation(tremolo));
+        default_map.insert(Finger::Little, TriggerDefinition::new(vec!(
             PlaybackType::Wave(PlaybackWave::new(notefreq::G_4, NoteShape::SineSquared))
-        )});
+        )).with_modulation(tremolo));
         map.insert(0, default_map);
 
-        let mut second_map = HashMap::new();
-        second_map.insert(Finger::Thumb, TriggerDefinition{notes: vec!(
-            PlaybackType::Wave(PlaybackWave::new(notefreq::C_4, NoteShape::SineSquared)),
-            PlaybackType::Wave(PlaybackWave::new(notefreq::E_4, NoteShape::SineSquared)),
-            PlaybackType::Wave(PlaybackWave::new(notefreq::G_4, NoteShape::SineSquared))
-        )});
-        second_map.insert(Finger::Index, TriggerDefinition{notes: vec!(
-            PlaybackType::Wave(PlaybackWave::new(notefreq::D_4, NoteShape::SineSquared)),
-            PlaybackType::Wave(PlaybackWave::new(notefreq::F_4, NoteShape::SineSquared)),
-            PlaybackType::Wave(PlaybackWave::new(notefreq::A_4, NoteShape::SineSquared))
-        )});
-        second_map.insert(Finger::Middle, TriggerDefinition{notes: vec!(
-            PlaybackType::Wave(PlaybackWave::new(notefreq::E_4, NoteShape::SineSquared)),
-            PlaybackType::Wave(PlaybackWave::new(notefreq::G_4, NoteShape::SineSquared)),
-            PlaybackType::Wave(PlaybackWave::new(notefreq::B_4, NoteShape::SineSquared))
-        )});
-        second_map.insert(Finger::Ring, TriggerDefinition{notes: vec!(
-            PlaybackType::Wave(PlaybackWave::new(notefreq::F_4, NoteShape::SineSquared)),
-            PlaybackType::Wave(PlaybackWave::new(notefreq::A_4, NoteShape::SineSquared)),
-            PlaybackType::Wave(PlaybackWave::new(notefreq::C_5, NoteShape::SineSquared))
-        )});
-        second_map.insert(Finger::Little, TriggerDefinition{notes: vec!(
-            PlaybackType::Wave(PlaybackWave::new(notefreq::G_4, NoteShape::SineSquared)),
-            PlaybackType::Wave(PlaybackWave::new(notefreq::B_4, NoteShape::SineSquared)),
-            PlaybackType::Wave(PlaybackWave::new(notefreq::D_5, NoteShape::SineSquared))
-        )});
-        map.insert(1, second_map);
-
-        let mut third_map = HashMap::new();
-        third_map.insert(Finger::Thumb, TriggerDefinition{notes: vec!(
-            PlaybackType::Sample(file_to_sample("/home/drew/Downloads/Strings/violin/violin_A4_1_fortissimo_arco-normal.mp3"))
-        )});
-        third_map.insert(Finger::Index, TriggerDefinition{notes: vec!(
-            PlaybackType::Sample(file_to_sample("/home/drew/Downloads/Strings/violin/violin_B4_1_fortissimo_arco-normal.mp3"))
-        )});
-        third_map.insert(Finger::Middle, TriggerDefinition{notes: vec!(
-            PlaybackType::Sample(file_to_sample("/home/drew/Downloads/Strings/violin/violin_Cs5_1_fortissimo_arco-normal.mp3"))
-        )});
-        third_map.insert(Finger::Ring, TriggerDefinition{notes: vec!(
-            PlaybackType::Sample(file_to_sample("/home/drew/Downloads/Strings/violin/violin_E5_1_fortissimo_arco-normal.mp3"))
-        )});
-        third_map.insert(Finger::Little, TriggerDefinition{notes: vec!(
-            PlaybackType::Sample(file_to_sample("/home/drew/Downloads/Strings/violin/violin_Fs5_1_fortissimo_arco-normal.mp3"))
-        )});
-        map.insert(2, third_map);
+        map.insert(CHORD_MAP, chord_map(&chords));
+        map.insert(2, sample_map(&violin));
+
+        let table = Arc::new(wavetable);
+        let wavetable_filter = FilterSettings::new(FilterMode::Lowpass, 300.0, 0.3)
+            .with_envelope(EnvelopeSettings { attack: 0.05, decay: 0.5, sustain: 0.3, release: 0.4 }, 2.0)
+            .with_cutoff_source(ControlSource::PalmY, 4.0)
+            .with_resonance_source(ControlSource::Grab)
+            .with_velocity(2.0);
+        let wavetable = |freq| TriggerDefinition::new(vec!(
+            PlaybackType::Wavetable(PlaybackWavetable::new(table.clone(), freq))
+        )).with_timbre(ControlSource::PalmZ).with_filter(wavetable_filter);
+        let mut fourth_map = HashMap::new();
+        fourth_map.insert(Finger::Thumb, wavetable(notefreq::C_4));
+        fourth_map.insert(Finger::Index, wavetable(notefreq::D_4));
+        fourth_map.insert(Finger::Middle, wavetable(notefreq::E_4));
+        fourth_map.insert(Finger::Ring, wavetable(notefreq::F_4));
+        fourth_map.insert(Finger::Little, wavetable(notefreq::G_4));
+        map.insert(3, fourth_map);
+
+        let fm = |freq, patch| TriggerDefinition::new(vec!(
+            PlaybackType::Fm(PlaybackFm::new(patch, freq))
+        )).with_timbre(ControlSource::Pinch);
+        let mut fifth_map = HashMap::new();
+        fifth_map.insert(Finger::Thumb, fm(notefreq::C_4, FmPatch::four_operator()));
+        fifth_map.insert(Finger::Index, fm(notefreq::D_4, FmPatch::four_operator()));
+        fifth_map.insert(Finger::Middle, fm(notefreq::E_4, FmPatch::two_operator()));
+        fifth_map.insert(Finger::Ring, fm(notefreq::F_4, FmPatch::two_operator()));
+        fifth_map.insert(Finger::Little, fm(notefreq::G_4, FmPatch::two_operator()));
+        map.insert(4, fifth_map);
+
 
 
         let state = State {
+            violin: violin,
+            effects: EffectsChain::new(effects, sample_rate),
+            limiter: Limiter::new(sample_rate),
+            master_gain: master_gain,
+            matrix: matrix,
+            calibration: calibration,
+            triggers: finger_triggers(&calibration),
+            hands: hands,
+            chords: chords,
+            octave: 0,
+            sustain_key: false,
+            sustain_latched: false,
+            sustain_fist: false,
+            arp: Arpeggiator::new(arp),
+            arp_held: HashMap::new(),
+            looper: Looper::new(looper),
+            loop_events: Vec::new(),
+            queued_loop: None,
+            transport: Transport::new(transport),
+            metronome: Metronome::new(),
+            clicks: Vec::new(),
+            clock_out: clock_out,
+            block_heard_at: Instant::now(),
+            block_position: 0,
+            send_state: ModState::new(),
             active_playback: Vec::new(),
             sample_rate: sample_rate,
             freq_map: map,
@@ -471,11 +1085,11 @@ impl State {
         state
     }
 
-    fn get_sample(&mut self, i: u32) -> f32 {
-        let mut val = 0f32;
+    fn get_sample(&mut self, i: u32) -> (f32, f32) {
+        let mut val = (0f32, 0f32);
         for note in &mut self.active_playback {
             let note_val = note.getSample(self.sample_rate, i);
-            val += note_val;
+            val = (val.0 + note_val.0, val.1 + note_val.1);
         }
 
         self.active_playback.retain(|x| x.should_retain());
@@ -484,32 +1098,372 @@ impl State {
             println!("Notes: {}", self.active_playback.len());
         }
 
-        if val > 1.0 {
-            val = 1.0;
-        }
         if self.active_playback.len() > 0 {
             val
         } else {
-            0 as f32
+            (0f32, 0f32)
+        }
+    }
+
+    // Master effects, gain and limiting over a block of mixed voices
+    fn process_block(&mut self, block: &mut [(f32, f32)]) {
+        self.effects.process(block, self.transport.settings.bpm);
+        for (frame, click) in block.iter_mut().zip(self.clicks.iter().chain(std::iter::repeat(&0.0))) {
+            *frame = ((frame.0 + click) * self.master_gain, (frame.1 + click) * self.master_gain);
+        }
+        self.clicks.clear();
+        self.limiter.process(block);
+    }
+
+    fn set_calibration(&mut self, calibration: HandCalibrations) {
+        self.calibration = calibration;
+        self.triggers = finger_triggers(&calibration);
+    }
+
+    // Lets go of the hand's notes when it stops playing
+    fn set_hand_role(&mut self, side: Side, role: HandRole) {
+        self.hands.set_role(side, role);
+        if role != HandRole::Play {
+            for finger in Finger::ALL {
+                let key = NoteKey { side: side, finger: finger };
+                self.remove_note(key);
+                self.arp.release(key);
+                self.arp_held.remove(&key);
+            }
+            for (key, trigger) in self.triggers.iter_mut() {
+                if key.side == side {
+                    *trigger = FingerTrigger::new(trigger.settings);
+                }
+            }
         }
     }
 
+    fn set_chords(&mut self, chords: ChordSettings) {
+        self.chords = chords;
+        self.freq_map.insert(CHORD_MAP, chord_map(&chords));
+    }
+
+    fn set_sample_points(&mut self, zone: usize, points: SamplePoints) {
+        if let Some(zone) = self.violin.zones.get_mut(zone) {
+            zone.sample.set_points(points);
+        }
+        self.freq_map.insert(2, sample_map(&self.violin));
+    }
+
+    fn set_release_mode(&mut self, zone: usize, release_mode: ReleaseMode) {
+        if let Some(zone) = self.violin.zones.get_mut(zone) {
+            zone.sample.set_release_mode(release_mode);
+        }
+        self.freq_map.insert(2, sample_map(&self.violin));
+    }
+
+    // Holds the zone's sample at its root note until stopped
+    fn audition(&mut self, zone: Option<usize>) {
+        for note in &mut self.active_playback {
+            if note.source == NoteSource::Audition && note.state != NoteState::Dying {
+                note.kill();
+            }
+        }
+        let zone = match zone.and_then(|x| self.violin.zones.get(x)) {
+            Some(zone) => zone,
+            None => return,
+        };
+        self.add_note(Note {
+            trigger: TriggerDefinition::new(vec!(PlaybackType::Sample(zone.sample.clone()))),
+            filter: None,
+            modulation: None,
+            mod_state: ModState::new(),
+            mods: ModValues::new(),
+            bend: 1.0,
+
+            key: NoteKey { side: Side::Right, finger: Finger::Thumb },
+            source: NoteSource::Audition,
+            sustained: false,
+
+            state: NoteState::Rising,
+            volume: 0.0,
+            attack: NOTE_ATTACK,
+            target_volume: 0.2,
+        });
+    }
+
     fn add_note(&mut self, note: Note) {
         self.active_playback.push(note);
         self.active_playback.retain(|x| x.should_retain())
     }
 
-    fn has_note(&self, finger: Finger) -> Option<usize> {
+    fn has_note(&self, key: NoteKey) -> Option<usize> {
         let index = self.active_playback.iter()
-            .position(|x| x.matches(finger));
+            .position(|x| x.matches(key));
         return index;
     }
 
-    fn remove_note(&mut self, finger: Finger) {
+    // With the sustain pedal down the note keeps sounding until the pedal
+    // comes up, and pressing the finger again starts a fresh note
+    fn remove_note(&mut self, key: NoteKey) {
         let index = self.active_playback.iter()
-            .position(|x| x.matches(finger));
+            .position(|x| x.matches(key));
         if let Some(index) = index {
-            self.active_playback[index].kill()
+            if self.sustain_down() {
+                self.active_playback[index].sustained = true;
+            } else {
+                self.active_playback[index].kill();
+                self.looper.record(LoopEvent::NoteOff(key));
+            }
+        }
+    }
+
+    fn set_transport(&mut self, settings: TransportSettings) {
+        self.transport.settings = settings;
+    }
+
+    // Call before filling each block with when its first sample is heard
+    fn start_block(&mut self, heard_at: Instant) {
+        self.block_heard_at = heard_at;
+        self.block_position = 0;
+    }
+
+    fn send_midi(&mut self, message: u8) {
+        if !self.transport.settings.clock_out {
+            return;
+        }
+        let offset = Duration::from_secs_f64(self.block_position as f64 / self.sample_rate as f64);
+        if let Some(clock_out) = &mut self.clock_out {
+            // a full buffer means the thread is stuck, so the clock just drops
+            let _ = clock_out.push(TimedMessage { at: self.block_heard_at + offset, message: message });
+        }
+    }
+
+    fn start_transport(&mut self, external: bool) {
+        self.transport.start(external);
+        self.arp.synced = true;
+        if !external {
+            self.send_midi(midi::START);
+        }
+        println!("transport started at {} bpm", self.transport.settings.bpm);
+    }
+
+    fn stop_transport(&mut self) {
+        if !self.transport.external {
+            self.send_midi(midi::STOP);
+        }
+        self.transport.stop();
+        self.arp.synced = false;
+        // nothing will come round to play a queued command
+        if let Some(command) = self.queued_loop.take() {
+            self.run_looper_command(command);
+        }
+        println!("transport stopped");
+    }
+
+    fn transport_command(&mut self, command: TransportCommand) {
+        match command {
+            TransportCommand::Start => self.start_transport(false),
+            TransportCommand::Stop => self.stop_transport(),
+        }
+    }
+
+    fn clock_in(&mut self, message: ClockMessage) {
+        if !self.transport.settings.clock_in {
+            return;
+        }
+        match message {
+            ClockMessage::Start => self.start_transport(true),
+            ClockMessage::Continue => {
+                self.transport.resume(true);
+                self.arp.synced = true;
+                println!("transport continued at {} bpm", self.transport.settings.bpm);
+            },
+            ClockMessage::Stop => {
+                if self.transport.external {
+                    self.stop_transport();
+                }
+            },
+            ClockMessage::Tick(timestamp) => {
+                let tick = self.transport.clock_in(timestamp);
+                self.handle_transport_tick(tick);
+            },
+        }
+    }
+
+    fn handle_transport_tick(&mut self, tick: TransportTick) {
+        // an incoming clock isn't echoed back out
+        if tick.clock && !self.transport.external {
+            self.send_midi(midi::CLOCK);
+        }
+        if let Some(beat) = tick.beat {
+            if self.transport.settings.metronome {
+                self.metronome.click(beat == 0, self.sample_rate);
+            }
+            self.arp.align_to_beat(self.sample_rate, self.transport.settings.bpm);
+            if beat == 0 {
+                if let Some(command) = self.queued_loop.take() {
+                    self.run_looper_command(command);
+                }
+            }
+        }
+    }
+
+    // Call once per sample
+    fn tick_transport(&mut self) {
+        let tick = self.transport.tick(self.sample_rate);
+        self.handle_transport_tick(tick);
+        let click = self.metronome.next(self.sample_rate) * self.transport.settings.metronome_gain;
+        self.clicks.push(click);
+        self.block_position += 1;
+    }
+
+    fn set_arp(&mut self, settings: ArpSettings) {
+        if settings.pattern != self.arp.settings.pattern {
+            // fingers still down start their notes again the next frame
+            self.arp.clear();
+            self.arp_held.clear();
+            self.stop_arp_notes();
+            let keys: Vec<NoteKey> = self.triggers.keys().copied().collect();
+            for key in keys {
+                self.remove_note(key);
+            }
+            for trigger in self.triggers.values_mut() {
+                *trigger = FingerTrigger::new(trigger.settings);
+            }
+        }
+        self.arp.settings = settings;
+    }
+
+    fn stop_arp_notes(&mut self) {
+        let sustain = self.sustain_down();
+        for note in &mut self.active_playback {
+            let sounding = note.state != NoteState::Dying && note.state != NoteState::Dead;
+            if note.source == NoteSource::Arp && sounding && !note.sustained {
+                if sustain {
+                    note.sustained = true;
+                } else {
+                    note.kill();
+                    self.looper.record(LoopEvent::NoteOff(note.key));
+                }
+            }
+        }
+    }
+
+    // Call once per sample
+    fn tick_arpeggiator(&mut self) {
+        match self.arp.tick(self.sample_rate, self.transport.settings.bpm) {
+            Some(ArpEvent::Stop) => self.stop_arp_notes(),
+            Some(ArpEvent::Start(keys)) => {
+                self.stop_arp_notes();
+                for key in keys {
+                    if let Some(note) = self.arp_held.get(&key) {
+                        let mut note = note.clone();
+                        note.source = NoteSource::Arp;
+                        note.attack = ARP_ATTACK;
+                        self.looper.record(LoopEvent::NoteOn(note.clone()));
+                        self.add_note(note);
+                    }
+                }
+            },
+            None => {},
+        }
+    }
+
+    fn sustain_down(&self) -> bool {
+        self.sustain_key || self.sustain_latched || self.sustain_fist
+    }
+
+    // Lets go of everything the pedal was holding once it comes up
+    fn update_sustain(&mut self) {
+        if self.sustain_down() {
+            return;
+        }
+        for note in &mut self.active_playback {
+            if note.sustained && note.state != NoteState::Dying && note.state != NoteState::Dead {
+                note.kill();
+                self.looper.record(LoopEvent::NoteOff(note.key));
+            }
+        }
+    }
+
+    // While the transport runs, loops start recording and playing on a
+    // downbeat so they line up with the bars
+    fn looper_command(&mut self, command: LooperCommand) {
+        let starts = command == LooperCommand::Record
+            || (command == LooperCommand::Play && self.looper.mode == LooperMode::Off);
+        if starts && self.transport.playing {
+            self.queued_loop = Some(command);
+            println!("looper {} waiting for the downbeat", command);
+            return;
+        }
+        self.run_looper_command(command);
+    }
+
+    fn run_looper_command(&mut self, command: LooperCommand) {
+        self.looper.command(command, self.sample_rate, &self.transport.settings);
+        if self.looper.mode == LooperMode::Off || command == LooperCommand::Undo {
+            self.stop_loop_notes();
+        }
+        println!("looper {} with {} layers", self.looper.mode, self.looper.layer_count());
+    }
+
+    fn stop_loop_notes(&mut self) {
+        for note in &mut self.active_playback {
+            if let NoteSource::Loop(_) = note.source {
+                if note.state != NoteState::Dying && note.state != NoteState::Dead {
+                    note.kill();
+                }
+            }
+        }
+    }
+
+    // Live and arpeggiated notes sounding now, which a loop pass can end on
+    fn held_notes(&self) -> Vec<Note> {
+        self.active_playback.iter()
+            .filter(|x| x.source == NoteSource::Live || x.source == NoteSource::Arp)
+            .filter(|x| x.state != NoteState::Dying && x.state != NoteState::Dead)
+            .cloned()
+            .collect()
+    }
+
+    // Call once per sample
+    fn tick_looper(&mut self) {
+        // notes held over the end of a pass end in its layer, and carry on
+        // into the next one if that records too
+        let held = if self.looper.is_recording() && self.looper.at_end() { self.held_notes() } else { Vec::new() };
+        for note in &held {
+            self.looper.record(LoopEvent::NoteOff(note.key));
+        }
+        let mut events = std::mem::take(&mut self.loop_events);
+        let wrapped = self.looper.tick(&mut events);
+        if wrapped {
+            for mut note in held {
+                note.state = NoteState::Rising;
+                note.volume = 0.0;
+                self.looper.record(LoopEvent::NoteOn(note));
+            }
+        }
+        for (layer, event) in events.drain(..) {
+            let source = NoteSource::Loop(layer);
+            let sounding = |x: &Note, key: NoteKey| x.source == source && x.key == key
+                && x.state != NoteState::Dying && x.state != NoteState::Dead;
+            match event {
+                LoopEvent::NoteOn(mut note) => {
+                    note.source = source;
+                    self.add_note(note);
+                },
+                LoopEvent::NoteOff(key) => {
+                    if let Some(note) = self.active_playback.iter_mut().find(|x| sounding(x, key)) {
+                        note.kill();
+                    }
+                },
+                LoopEvent::Controls(key, mods) => {
+                    for note in self.active_playback.iter_mut().filter(|x| sounding(x, key)) {
+                        note.apply_mods(mods);
+                    }
+                },
+            }
+        }
+        self.loop_events = events;
+        // notes still held when the loop comes round would hang otherwise
+        if wrapped {
+            self.stop_loop_notes();
         }
     }
 }
@@ -520,62 +1474,122 @@ impl fmt::Display for LeapRustVector {
     }
 }
 
-fn finger_to_usize(finger: Finger) -> usize {
-    return finger as usize;
+const MAX_OCTAVE_SHIFT: i32 = 3;
+const NOTE_ATTACK: f32 = 0.000002;
+// arpeggiated steps are short, so they come in quicker
+const ARP_ATTACK: f32 = 0.0005;
+// grab strength that puts the pedal down, and lets it up again
+const SUSTAIN_GRAB_ON: f32 = 0.8;
+const SUSTAIN_GRAB_OFF: f32 = 0.5;
+// loudness of the softest press relative to the hardest
+const MIN_VELOCITY_GAIN: f32 = 0.3;
+const FULL_NOTE_VOLUME: f32 = 0.2;
+
+fn midi_velocity(velocity: f32) -> u8 {
+    (1.0 + velocity * 126.0).round() as u8
 }
 
-fn is_finger_active(frame: &LeapRustFrame, finger: Finger, fing_index: usize) -> bool {
-    let bottom = if finger != Finger::Thumb { 200f32 } else { 190f32 };
-    if frame.handCount == 0 {
-        return false;
-    }
-    if frame.handCount == 1 && frame.hands[0].isLeft == 1 {
-        return false;
-    }
-    let right_hand = if frame.handCount == 1 {
-        &frame.hands[0]
-    } else {
-        if frame.hands[0].isLeft == 0 {
-            &frame.hands[0]
-        } else {
-            &frame.hands[1]
-        }
-    };
-    let should_be_present = frame.handCount > 0 &&
-        right_hand.fingerCount > fing_index.try_into().unwrap() &&
-        right_hand.fingers[fing_index].tipPosition.y < bottom;
-    return should_be_present
+// The velocity of the press while the finger is held down
+fn is_finger_active(hands: &HandView, key: NoteKey, trigger: &mut FingerTrigger) -> Option<f32> {
+    trigger.update(hands.finger(key.side, key.finger), hands.timestamp)
 }
 
-fn handle_finger(frame: &LeapRustFrame, finger: Finger, notes: &mut State) {
-    let fing_index = finger_to_usize(finger);
-    let has_note = notes.has_note(finger);
-    let should_be_present = is_finger_active(frame, finger, fing_index);
+fn handle_finger(hands: &HandView, key: NoteKey, notes: &mut State) {
+    // with the arpeggiator on, fingers hold notes for it to play instead
+    let arp = notes.arp.is_active();
+    let has_note = if arp { notes.arp_held.contains_key(&key) } else { notes.has_note(key).is_some() };
+    let trigger = notes.triggers.get_mut(&key).expect("no trigger for finger");
+    let velocity = is_finger_active(hands, key, trigger);
+    let should_be_present = velocity.is_some();
+    let layout_finger = notes.hands.layout_finger(key.side, key.finger);
     let trigger_def = notes.freq_map
         .get(&notes.selected_map).expect("poo")
-        .get(&finger).expect("asdf");
-    if has_note.is_none() && should_be_present {
-        println!("adding {} with x {}", finger, frame.hands[0].fingers[fing_index].tipPosition.x);
-        notes.add_note(Note {
-            trigger: trigger_def.clone(),
-
-            finger,
+        .get(&layout_finger).expect("asdf");
+    let leap_finger = hands.finger(key.side, key.finger);
+    // pinching while pressing a chord finger plays its seventh chord
+    let seventh = notes.selected_map == CHORD_MAP && notes.chords.pinch_seventh
+        && hands.hand(key.side).map_or(false, |x| x.pinchStrength > SEVENTH_PINCH);
+    // a modulating hand steers the controls with its matching finger
+    let control_side = notes.hands.control_side(hands, key.side);
+    let control_hand = hands.hand(control_side);
+    let control_finger = hands.finger(control_side, key.finger);
+    let position = leap_finger.map_or(LeapRustVector { x: 0.0, y: 0.0, z: 0.0 }, |x| x.tipPosition);
+    if !has_note && should_be_present {
+        let velocity = velocity.unwrap_or(0.0);
+        println!("adding {} {} with x {} velocity {}", key.side, key.finger, position.x, velocity);
+        let mut trigger = if seventh {
+            chord_trigger(&notes.chords, layout_finger, true)
+        } else {
+            trigger_def.clone()
+        };
+        trigger.pick_layer(&notes.violin, midi_velocity(velocity));
+        trigger.transpose(notes.hands.transpose(key.side) * 2f32.powi(notes.octave));
+        let mut filter = trigger_def.filter.map(VoiceFilter::new);
+        if let Some(filter) = &mut filter {
+            filter.set_velocity(velocity);
+        }
+        let mut note = Note {
+            trigger: trigger,
+            filter: filter,
+            modulation: trigger_def.modulation.map(VoiceModulation::new),
+            mod_state: ModState::new(),
+            mods: ModValues::new(),
+            bend: 1.0,
+
+            key,
+            source: NoteSource::Live,
+            sustained: false,
 
             state: NoteState::Rising,
             volume: 0.0,
-            target_volume: 0.2,
+            attack: NOTE_ATTACK,
+            target_volume: FULL_NOTE_VOLUME * (MIN_VELOCITY_GAIN + (1.0 - MIN_VELOCITY_GAIN) * velocity),
+        };
+        if let (Some(hand), Some(leap_finger)) = (control_hand, control_finger) {
+            note.update_controls(hand, leap_finger, &notes.matrix, notes.calibration.hand(control_side));
+        }
+        if arp {
+            notes.arp.hold(key, note.trigger.pitch());
+            notes.arp_held.insert(key, note);
+        } else {
+            notes.looper.record(LoopEvent::NoteOn(note.clone()));
+            notes.add_note(note);
+        }
+    } else if has_note && !should_be_present {
+        println!("removing {} {}", key.side, key.finger);
+        if arp {
+            notes.arp.release(key);
+            notes.arp_held.remove(&key);
+        } else {
+            notes.remove_note(key);
+        }
+    } else if has_note && should_be_present {
+        if let (Some(hand), Some(leap_finger)) = (control_hand, control_finger) {
+            for note in notes.active_playback.iter_mut().filter(|x| x.follows(key)) {
+                note.update_controls(hand, leap_finger, &notes.matrix, notes.calibration.hand(control_side));
+                notes.looper.record(LoopEvent::Controls(key, note.mods));
+            }
+        }
+    }
+}
 
-            position: frame.hands[0].fingers[fing_index].tipPosition,
-            phase: 0.0,
-        });
-    } else if has_note.is_some() && !should_be_present {
-        println!("removing {}", finger);
-        notes.remove_note(finger);
-    } else if has_note.is_some() && should_be_present {
-        // check for bends
-        let finger_position = frame.hands[0].fingers[fing_index].tipPosition;
-        let note = &mut (notes.active_playback[has_note.unwrap()]);
-        note.update_position(finger_position)
+// Pressing a finger of the selecting hand picks a map, little finger first
+fn select_map(hands: &HandView, side: Side, notes: &mut State) {
+    if hands.hand(side).is_none() {
+        return;
+    }
+    let pressed = |finger: Finger| hands.finger(side, finger)
+        .map_or(false, |x| notes.calibration.hand(side).is_pressed(finger.index(), x));
+    if pressed(Finger::Thumb) {
+        notes.selected_map = 4;
+    } else if pressed(Finger::Index) {
+        notes.selected_map = 3;
+    } else if pressed(Finger::Middle) {
+        notes.selected_map = 2;
+    } else if pressed(Finger::Ring) {
+        notes.selected_map = 1;
+    } else if pressed(Finger::Little) {
+        notes.selected_map = 0;
     }
 }
 
@@ -584,29 +1598,74 @@ fn read_and_play(frame_ptr: *mut LeapRustFrame, notes: &mut State) {
     unsafe {
         frame = &(*frame_ptr);
     }
-    for hand_index in 0..frame.handCount {
-        let hand = &frame.hands[hand_index as usize];
-        if hand.isLeft == 0 {
+    let hands = HandView::new(frame);
+    for side in [Side::Left, Side::Right] {
+        if notes.hands.role(side) == HandRole::MapSelect {
+            select_map(&hands, side, notes);
+        }
+    }
+
+    // a fist made with a hand that isn't playing holds the sustain pedal
+    let grab = [Side::Left, Side::Right].into_iter()
+        .filter(|x| notes.hands.role(*x) != HandRole::Play)
+        .filter_map(|x| hands.hand(x))
+        .map(|x| x.grabStrength)
+        .fold(0f32, f32::max);
+    let fist = if notes.sustain_fist { grab > SUSTAIN_GRAB_OFF } else { grab > SUSTAIN_GRAB_ON };
+    if fist != notes.sustain_fist {
+        println!("sustain fist {}", fist);
+        notes.sustain_fist = fist;
+        notes.update_sustain();
+    }
+
+    for side in [Side::Left, Side::Right] {
+        if notes.hands.role(side) != HandRole::Play {
             continue;
         }
-        if hand.fingers[2].tipPosition.y < 200.0 {
-            notes.selected_map = 2;
-        } else if hand.fingers[3].tipPosition.y < 200.0 {
-            notes.selected_map = 1;
-        } else if hand.fingers[4].tipPosition.y < 200.0 {
-            notes.selected_map = 0;
+        for finger in Finger::ALL {
+            handle_finger(&hands, NoteKey { side: side, finger: finger }, notes);
+        }
+    }
+
+    // the first playing hand sets the arpeggiator's rate and gate
+    let arp_hand = [Side::Right, Side::Left].into_iter()
+        .find(|x| notes.hands.role(*x) == HandRole::Play && hands.hand(*x).is_some());
+    if let Some(side) = arp_hand {
+        if let (Some(hand), Some(index)) = (hands.hand(side), hands.finger(side, Finger::Index)) {
+            let settings = notes.arp.settings;
+            let calibration = notes.calibration.hand(side);
+            notes.arp.set_controls(settings.rate_source.read(hand, index, calibration), settings.gate_source.read(hand, index, calibration));
         }
     }
 
-    handle_finger(frame, Finger::Thumb, notes);
-    handle_finger(frame, Finger::Index, notes);
-    handle_finger(frame, Finger::Middle, notes);
-    handle_finger(frame, Finger::Ring, notes);
-    handle_finger(frame, Finger::Little, notes);
-    //handle_finger(frame, 5, 1174.66f32, collector, notes);
+    // the sends follow the modulating hand if there is one, otherwise the
+    // playing right hand
+    let send_side = [Side::Left, Side::Right].into_iter()
+        .find(|x| notes.hands.role(*x) == HandRole::Modulation && hands.hand(*x).is_some())
+        .unwrap_or(Side::Right);
+    if let (Some(hand), Some(index)) = (hands.hand(send_side), hands.finger(send_side, Finger::Index)) {
+        let sends = notes.send_state.update(&notes.matrix, hand, index, notes.calibration.hand(send_side));
+        notes.effects.set_sends(sends.reverb_send, sends.delay_send, sends.chorus_send);
+    }
 }
 
-pub fn set_up_cpal(frame: *mut LeapRustFrame, mut ring_buf: Consumer<AppEvent>) -> Stream {
+pub fn set_up_cpal(
+    frame: *mut LeapRustFrame,
+    mut ring_buf: Consumer<AppEvent>,
+    violin: Multisample,
+    wavetable: Wavetable,
+    effects: EffectSettings,
+    master_gain: f32,
+    matrix: ModMatrix,
+    calibration: HandCalibrations,
+    hands: HandSettings,
+    chords: ChordSettings,
+    arp: ArpSettings,
+    looper: LoopSettings,
+    transport: TransportSettings,
+    mut clock_in: Consumer<ClockMessage>,
+    clock_out: Option<Producer<TimedMessage>>
+) -> Stream {
     let host = cpal::default_host();
     let device = host.default_output_device().expect("no output device available");
     let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
@@ -618,34 +1677,115 @@ pub fn set_up_cpal(frame: *mut LeapRustFrame, mut ring_buf: Consumer<AppEvent>)
     let sample_format = supported_config.sample_format();
     let config: StreamConfig = supported_config.into();
     let mut i = 0;
-    let mut state = State::new(config.sample_rate.0);
+    let channels = config.channels as usize;
+    let mut state = State::new(config.sample_rate.0, violin, wavetable, effects, master_gain, matrix, calibration, hands, chords, arp, looper, transport, clock_out);
+    let mut block: Vec<(f32, f32)> = Vec::new();
 
     let mut last_timestamp: i32 = 0;
     let aframe: AtomicPtr<LeapRustFrame> = AtomicPtr::new(frame);
-    let create_audio_stream = move |data: &mut [f32], _cb: &cpal::OutputCallbackInfo| {
+    let create_audio_stream = move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
         let tframe = aframe.load(Ordering::Relaxed);
-        if let Some(app_event) = ring_buf.pop().ok() {
+        let timestamp = info.timestamp();
+        let latency = timestamp.playback.duration_since(&timestamp.callback).unwrap_or_default();
+        state.start_block(Instant::now() + latency);
+        while let Some(app_event) = ring_buf.pop().ok() {
             match app_event {
                 AppEvent::SetShape(shape) => {
                     println!("popping event");
                     state.shape = shape;
                 }
+                AppEvent::SetSamplePoints(zone, points) => {
+                    state.set_sample_points(zone, points);
+                }
+                AppEvent::SetReleaseMode(zone, release_mode) => {
+                    state.set_release_mode(zone, release_mode);
+                }
+                AppEvent::Audition(zone) => {
+                    state.audition(zone);
+                }
+                AppEvent::SetEffect(param, value) => {
+                    state.effects.settings.set(param, value);
+                }
+                AppEvent::SetMasterGain(gain) => {
+                    state.master_gain = gain;
+                }
+                AppEvent::SetCalibration(calibration) => {
+                    state.set_calibration(calibration);
+                }
+                AppEvent::SetHandRole(side, role) => {
+                    state.set_hand_role(side, role);
+                }
+                AppEvent::SelectMap(map) => {
+                    state.selected_map = map;
+                }
+                AppEvent::SetChords(chords) => {
+                    state.set_chords(chords);
+                }
+                AppEvent::SetSustainKey(down) => {
+                    state.sustain_key = down;
+                    state.update_sustain();
+                }
+                AppEvent::ToggleSustain => {
+                    state.sustain_latched = !state.sustain_latched;
+                    println!("sustain latched {}", state.sustain_latched);
+                    state.update_sustain();
+                }
+                AppEvent::SetArp(settings) => {
+                    state.set_arp(settings);
+                }
+                AppEvent::Looper(command) => {
+                    state.looper_command(command);
+                }
+                AppEvent::SetLoopSettings(settings) => {
+                    state.looper.settings = settings;
+                }
+                AppEvent::SetTransport(settings) => {
+                    state.set_transport(settings);
+                }
+                AppEvent::Transport(command) => {
+                    state.transport_command(command);
+                }
+                AppEvent::StepMap(step) => {
+                    let maps = state.freq_map.len() as i32;
+                    state.selected_map = (state.selected_map + step).rem_euclid(maps);
+                }
+                AppEvent::ShiftOctave(octaves) => {
+                    state.octave = (state.octave + octaves).max(-MAX_OCTAVE_SHIFT).min(MAX_OCTAVE_SHIFT);
+                    println!("octave {}", state.octave);
+                }
                 _ => {}
             }
         }
-        for sample in data.iter_mut() {
+        while let Ok(message) = clock_in.pop() {
+            state.clock_in(message);
+        }
+        block.clear();
+        for _ in 0..data.len() / channels {
             let frame_stamp = unsafe { (*tframe).timestamp };
             if last_timestamp != frame_stamp {
                 read_and_play(tframe, &mut state);
                 last_timestamp = frame_stamp;
             }
-            let val = state.get_sample(i);
-            *sample = Sample::from(&val);
+            state.tick_transport();
+            state.tick_arpeggiator();
+            state.tick_looper();
+            block.push(state.get_sample(i));
             i = i + 1;
             if state.active_playback.len() == 0 {
                 i = 0;
             }
         }
+        state.process_block(&mut block);
+        for (frame, (left, right)) in data.chunks_mut(channels).zip(block.iter()) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let val = match (channels, channel % 2) {
+                    (1, _) => (left + right) / 2.0,
+                    (_, 0) => *left,
+                    _ => *right,
+                };
+                *sample = Sample::from(&val);
+            }
+        }
     };
     let stream = match sample_format {
         SampleFormat::F32 => panic!("f32"),
@@ -1,25 +1,118 @@
+use crate::arp::ArpSettings;
+use crate::calibration::{Calibration, Calibrator, CalibrationStep, HandCalibrations, PROFILE_PATH};
+use crate::chord::ChordSettings;
 use crate::leaprust::{LeapRustFrame, LeapRustBoneType_TYPE_INTERMEDIATE};
-use crate::lrcpal::{NoteShape};
+use crate::effects::{EffectParam, EffectSettings};
+use crate::gesture::{GestureAction, GestureBindings, GestureRecognizer};
+use crate::handview::{HandRole, HandSettings, HandView, Side};
+use crate::trigger::DetectionMode;
+use crate::looper::{LoopSettings, LooperCommand};
+use crate::lrcpal::{NoteShape, ReleaseMode, SamplePoints};
+use crate::multisample::Multisample;
+use crate::preset::{Preset, PRESET_PATH};
+use crate::transport::{TransportCommand, TransportSettings};
+use crate::wrist::WristSwitch;
 
 use vizia::vg;
 use vizia::prelude::{
+    Button,
     Canvas,
+    Code,
     Context,
     DataContext,
     DrawContext,
     Event,
     EventContext,
     Handle,
+    HStack,
+    Label,
+    LayoutModifiers,
     Lens,
     Model,
+    MouseButton,
+    Pixels,
+    Slider,
+    Stretch,
     View,
+    VStack,
+    WindowEvent,
 };
 use rtrb::Producer;
 
 #[derive(Copy, Clone)]
 pub enum AppEvent {
     FrameUpdate,
-    SetShape(NoteShape)
+    SetShape(NoteShape),
+    SelectZone(usize),
+    MoveMarker(SampleMarker, usize),
+    CommitSamplePoints,
+    SetLoop,
+    ClearLoop,
+    SetSamplePoints(usize, SamplePoints),
+    ToggleReleaseMode,
+    SetReleaseMode(usize, ReleaseMode),
+    ToggleAudition,
+    Audition(Option<usize>),
+    SetEffect(EffectParam, f32),
+    SetMasterGain(f32),
+    SavePreset,
+    StartCalibration,
+    NextCalibrationStep,
+    SetDetectionMode(DetectionMode),
+    SetMinHold(i32),
+    SetMinSpeed(f32),
+    SetCalibration(HandCalibrations),
+    CycleHandRole(Side),
+    SetHandRole(Side, HandRole),
+    SelectMap(i32),
+    SetChords(ChordSettings),
+    StepMap(i32),
+    ShiftOctave(i32),
+    SetSustainKey(bool),
+    ToggleSustain,
+    SetArp(ArpSettings),
+    Looper(LooperCommand),
+    SetLoopSettings(LoopSettings),
+    SetTransport(TransportSettings),
+    Transport(TransportCommand),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SampleMarker {
+    Start,
+    LoopStart,
+    LoopEnd,
+    End,
+}
+
+impl SampleMarker {
+    // None for a loop marker on a sample without a loop
+    fn position(&self, points: &SamplePoints) -> Option<usize> {
+        match self {
+            SampleMarker::Start => Some(points.start),
+            SampleMarker::LoopStart => points.loop_start,
+            SampleMarker::LoopEnd => points.loop_end,
+            SampleMarker::End => Some(points.end),
+        }
+    }
+
+    // Moves the marker without letting it cross its neighbours. Without a
+    // loop, start and end only stop at each other.
+    fn moved(&self, points: SamplePoints, position: usize) -> SamplePoints {
+        let mut points = points;
+        match (self, points.loop_start, points.loop_end) {
+            (SampleMarker::Start, loop_start, _) => points.start = position.min(loop_start.unwrap_or(points.end)),
+            (SampleMarker::LoopStart, Some(_), Some(loop_end)) => {
+                points.loop_start = Some(position.max(points.start).min(loop_end.saturating_sub(1)))
+            },
+            (SampleMarker::LoopEnd, Some(loop_start), Some(_)) => {
+                points.loop_end = Some(position.max(loop_start + 1).min(points.end))
+            },
+            (SampleMarker::End, _, loop_end) => points.end = position.max(loop_end.unwrap_or(points.start)),
+            _ => {}
+        }
+        points
+    }
 }
 
 
@@ -29,7 +122,110 @@ pub struct AppData {
     pub frame: *mut LeapRustFrame,
     pub placeholder: bool,
     pub note_shape: NoteShape,
-    pub ring_buf: Producer<AppEvent>
+    pub ring_buf: Producer<AppEvent>,
+    pub instrument: Multisample,
+    pub preset: Preset,
+    pub editor_zone: usize,
+    pub editor_points: SamplePoints,
+    pub editor_revision: i32,
+    pub editor_release: String,
+    pub auditioning: bool,
+    pub effects: EffectSettings,
+    pub master_gain: f32,
+    pub calibration: HandCalibrations,
+    pub calibrator: Calibrator,
+    pub calibration_prompt: String,
+    pub hands: HandSettings,
+    pub hand_roles: String,
+    pub wrist: WristSwitch,
+    pub chords: ChordSettings,
+    pub chord_name: String,
+    pub gestures: GestureRecognizer,
+    pub gesture_bindings: GestureBindings,
+    pub sustain_key: bool,
+    pub arp: ArpSettings,
+    pub arp_name: String,
+    pub looper: LoopSettings,
+    pub looper_name: String,
+    pub transport: TransportSettings,
+    pub transport_name: String,
+}
+
+impl AppData {
+    // The wrist picks the map here rather than on the audio thread, so the
+    // view can show the zone the audio thread is told about
+    fn update_wrist(&mut self) {
+        let hands = HandView::new(unsafe { &*(self.frame) });
+        let hand = [Side::Left, Side::Right].into_iter()
+            .find(|x| self.hands.role(*x) == HandRole::WristSelect)
+            .and_then(|x| hands.hand(x));
+        let hand = match hand {
+            Some(hand) => hand,
+            None => {
+                self.wrist.angle = None;
+                return;
+            }
+        };
+        let previous = self.wrist.active;
+        if let Some(map) = self.wrist.update(hand) {
+            if self.wrist.active != previous {
+                println!("wrist selected map {}", map);
+                self.ring_buf.push(AppEvent::SelectMap(map)).expect("Failed to push");
+            }
+        }
+    }
+}
+
+impl AppData {
+    fn set_transport(&mut self, settings: TransportSettings) {
+        self.transport = settings;
+        self.transport_name = settings.describe();
+        self.ring_buf.push(AppEvent::SetTransport(settings)).expect("Failed to push");
+    }
+
+    fn update_calibrations(&mut self, change: impl Fn(&mut Calibration)) {
+        change(&mut self.calibration.left);
+        change(&mut self.calibration.right);
+        self.calibration.save(PROFILE_PATH);
+        self.ring_buf.push(AppEvent::SetCalibration(self.calibration)).expect("Failed to push");
+    }
+
+    fn commit_sample_points(&mut self, points: SamplePoints) {
+        let zone = self.editor_zone;
+        let sample = &mut self.instrument.zones[zone].sample;
+        sample.set_points(points);
+        self.editor_points = sample.points();
+        self.editor_revision += 1;
+        self.ring_buf.push(AppEvent::SetSamplePoints(zone, self.editor_points)).expect("Failed to push");
+    }
+
+    fn set_sustain_key(&mut self, down: bool) {
+        if self.sustain_key != down {
+            self.sustain_key = down;
+            self.ring_buf.push(AppEvent::SetSustainKey(down)).expect("Failed to push");
+        }
+    }
+
+    fn update_gestures(&mut self) {
+        let hands = HandView::new(unsafe { &*(self.frame) });
+        for (side, gesture) in self.gestures.update(&hands, &self.hands) {
+            let action = self.gesture_bindings.action(gesture);
+            println!("{} hand {} -> {}", side, gesture, action);
+            let event = match action {
+                GestureAction::Nothing => continue,
+                GestureAction::NextShape => {
+                    self.note_shape = self.note_shape.next();
+                    AppEvent::SetShape(self.note_shape)
+                },
+                GestureAction::NextMap => AppEvent::StepMap(1),
+                GestureAction::PreviousMap => AppEvent::StepMap(-1),
+                GestureAction::OctaveUp => AppEvent::ShiftOctave(1),
+                GestureAction::OctaveDown => AppEvent::ShiftOctave(-1),
+                GestureAction::ToggleSustain => AppEvent::ToggleSustain,
+            };
+            self.ring_buf.push(event).expect("Failed to push");
+        }
+    }
 }
 
 // Describe how the data can be mutated
@@ -38,16 +234,195 @@ impl Model for AppData {
         event.map(|app_event, _| match app_event {
             AppEvent::FrameUpdate => {
                 self.timestamp = unsafe { (*(self.frame)).timestamp };
+                if self.calibrator.step != CalibrationStep::Off {
+                    self.calibrator.record(unsafe { &*(self.frame) });
+                }
+                self.update_wrist();
+                self.update_gestures();
             },
             AppEvent::SetShape(shape) => {
                 self.note_shape = *shape;
                 println!("pushing event");
                 self.ring_buf.push(*app_event).expect("Failed to push");
-            }
+            },
+            AppEvent::SelectZone(zone) => {
+                if let Some(sample_zone) = self.instrument.zones.get(*zone) {
+                    self.editor_zone = *zone;
+                    self.editor_points = sample_zone.sample.points();
+                    self.editor_release = sample_zone.sample.release_mode().to_string();
+                    self.editor_revision += 1;
+                }
+            },
+            AppEvent::MoveMarker(marker, position) => {
+                self.editor_points = marker.moved(self.editor_points, *position);
+                self.editor_revision += 1;
+            },
+            AppEvent::CommitSamplePoints => {
+                self.commit_sample_points(self.editor_points);
+            },
+            AppEvent::SetLoop => {
+                self.commit_sample_points(with_loop(self.editor_points));
+            },
+            AppEvent::ClearLoop => {
+                self.commit_sample_points(SamplePoints { loop_start: None, loop_end: None, ..self.editor_points });
+            },
+            AppEvent::ToggleReleaseMode => {
+                let zone = self.editor_zone;
+                let sample = &mut self.instrument.zones[zone].sample;
+                let release_mode = match sample.release_mode() {
+                    ReleaseMode::Loop => ReleaseMode::Tail,
+                    ReleaseMode::Tail => ReleaseMode::Loop,
+                };
+                sample.set_release_mode(release_mode);
+                self.editor_release = release_mode.to_string();
+                self.ring_buf.push(AppEvent::SetReleaseMode(zone, release_mode)).expect("Failed to push");
+            },
+            AppEvent::ToggleAudition => {
+                self.auditioning = !self.auditioning;
+                let zone = if self.auditioning { Some(self.editor_zone) } else { None };
+                self.ring_buf.push(AppEvent::Audition(zone)).expect("Failed to push");
+            },
+            AppEvent::SetEffect(param, value) => {
+                self.effects.set(*param, *value);
+                self.ring_buf.push(*app_event).expect("Failed to push");
+            },
+            AppEvent::SetMasterGain(gain) => {
+                self.master_gain = *gain;
+                self.ring_buf.push(*app_event).expect("Failed to push");
+            },
+            AppEvent::SetSamplePoints(_, _) | AppEvent::SetReleaseMode(_, _) | AppEvent::Audition(_) => {
+                self.ring_buf.push(*app_event).expect("Failed to push");
+            },
+            AppEvent::StartCalibration => {
+                self.calibrator.start(self.calibration, &self.hands);
+                self.calibration_prompt = self.calibrator.prompt();
+            },
+            AppEvent::NextCalibrationStep => {
+                if let Some(calibration) = self.calibrator.advance() {
+                    self.calibration = calibration;
+                    calibration.save(PROFILE_PATH);
+                    self.ring_buf.push(AppEvent::SetCalibration(calibration)).expect("Failed to push");
+                }
+                self.calibration_prompt = self.calibrator.prompt();
+            },
+            // detection and timing are set for both hands at once
+            AppEvent::SetDetectionMode(mode) => {
+                self.update_calibrations(|x| x.detection = *mode);
+            },
+            AppEvent::SetMinHold(micros) => {
+                self.update_calibrations(|x| x.min_hold = *micros);
+            },
+            AppEvent::SetMinSpeed(speed) => {
+                self.update_calibrations(|x| x.min_speed = *speed);
+            },
+            AppEvent::SetCalibration(_) => {
+                self.ring_buf.push(*app_event).expect("Failed to push");
+            },
+            AppEvent::CycleHandRole(side) => {
+                let role = self.hands.role(*side).next();
+                self.hands.set_role(*side, role);
+                self.hand_roles = hand_roles(&self.hands);
+                self.ring_buf.push(AppEvent::SetHandRole(*side, role)).expect("Failed to push");
+            },
+            AppEvent::SetLoopSettings(settings) => {
+                self.looper = *settings;
+                self.looper_name = settings.describe();
+                self.ring_buf.push(*app_event).expect("Failed to push");
+            },
+            AppEvent::SetTransport(settings) => {
+                self.set_transport(*settings);
+            },
+            AppEvent::SetArp(settings) => {
+                self.arp = *settings;
+                self.arp_name = settings.describe();
+                self.ring_buf.push(*app_event).expect("Failed to push");
+            },
+            AppEvent::SetChords(chords) => {
+                self.chords = *chords;
+                self.chord_name = chords.describe();
+                self.ring_buf.push(*app_event).expect("Failed to push");
+            },
+            AppEvent::SetHandRole(_, _) | AppEvent::SelectMap(_) | AppEvent::StepMap(_) | AppEvent::ShiftOctave(_)
+                | AppEvent::SetSustainKey(_) | AppEvent::ToggleSustain | AppEvent::Looper(_)
+                | AppEvent::Transport(_) => {
+                self.ring_buf.push(*app_event).expect("Failed to push");
+            },
+            AppEvent::SavePreset => {
+                self.instrument.store_preset(&mut self.preset, "violin");
+                self.effects.store_preset(&mut self.preset);
+                self.preset.set("master.gain", self.master_gain);
+                self.hands.store_preset(&mut self.preset);
+                self.wrist.store_preset(&mut self.preset);
+                self.chords.store_preset(&mut self.preset);
+                self.gesture_bindings.store_preset(&mut self.preset);
+                self.arp.store_preset(&mut self.preset);
+                self.looper.store_preset(&mut self.preset);
+                self.transport.store_preset(&mut self.preset);
+                match self.preset.save(PRESET_PATH) {
+                    Ok(()) => println!("saved preset to {}", PRESET_PATH),
+                    Err(err) => eprintln!("couldn't save preset to {}: {}", PRESET_PATH, err),
+                }
+            },
+        });
+        // holding space is the sustain pedal; only changes are sent on as
+        // the key repeats while held
+        event.map(|window_event, _| match window_event {
+            WindowEvent::KeyDown(Code::Space, _) => self.set_sustain_key(true),
+            WindowEvent::KeyUp(Code::Space, _) => self.set_sustain_key(false),
+            _ => {}
         });
     }
 }
 
+pub fn hand_roles(hands: &HandSettings) -> String {
+    format!("Left: {}  Right: {}", hands.left, hands.right)
+}
+
+// Views one effect parameter of AppData, scaled to 0..1 for a slider
+#[derive(Debug, Clone, Copy)]
+pub struct EffectLens(EffectParam);
+
+impl Lens for EffectLens {
+    type Source = AppData;
+    type Target = f32;
+
+    fn view<O, F: FnOnce(Option<&Self::Target>) -> O>(&self, source: &Self::Source, map: F) -> O {
+        let value = self.0.to_normalized(source.effects.get(self.0));
+        map(Some(&value))
+    }
+}
+
+pub fn effects_panel(cx: &mut Context) {
+    HStack::new(cx, |cx| {
+        VStack::new(cx, |cx| {
+            Label::new(cx, "Master");
+            // master gain runs from silent to 4x
+            Slider::new(cx, AppData::master_gain.map(|gain| gain / 4.0))
+                .on_changing(|cx, value| cx.emit(AppEvent::SetMasterGain(value * 4.0)));
+        })
+            .width(Pixels(80.0));
+        for param in EffectParam::ALL {
+            if param == EffectParam::DelaySync {
+                Button::new(cx, |cx| {
+                    let synced = cx.data::<AppData>().map_or(false, |x| x.effects.delay_sync);
+                    cx.emit(AppEvent::SetEffect(EffectParam::DelaySync, if synced { 0.0 } else { 1.0 }))
+                }, |cx| Label::new(cx, "Sync"));
+                continue;
+            }
+            VStack::new(cx, move |cx| {
+                Label::new(cx, &param.to_string());
+                Slider::new(cx, EffectLens(param))
+                    .on_changing(move |cx, value| {
+                        cx.emit(AppEvent::SetEffect(param, param.from_normalized(value)))
+                    });
+            })
+                .width(Pixels(80.0));
+        }
+    })
+        .child_space(Stretch(1.0))
+        .col_between(Pixels(4.0));
+}
+
 pub struct CustomView { }
 
 impl CustomView {
@@ -64,15 +439,14 @@ struct LeapCoordConverter {
     t: f32,
     l: f32,
     b: f32,
-    r: f32
+    r: f32,
+    calibration: Calibration,
 }
 
 impl LeapCoordConverter {
     fn convert(&self, x: f32, y: f32) -> (f32, f32) {
-        let std_leap_y_max = 650f32;
-        let std_leap_y_min = 10f32;
-        let std_leap_x_max = 330f32;
-        let std_leap_x_min = -420f32;
+        let (std_leap_x_min, std_leap_x_max) = self.calibration.x;
+        let (std_leap_y_min, std_leap_y_max) = self.calibration.y;
         let std_leap_height = std_leap_y_max - std_leap_y_min;
         let std_leap_width = std_leap_x_max - std_leap_x_min;
 
@@ -87,32 +461,11 @@ impl LeapCoordConverter {
 }
 
 
-static mut finger_t: f32 = f32::NEG_INFINITY;
-static mut finger_l: f32 = f32::INFINITY;
-static mut finger_b: f32 = f32::INFINITY;
-static mut finger_r: f32 = f32::NEG_INFINITY;
-
 impl View for CustomView {
     fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
         if let Some(app_data) = cx.data::<AppData>() {
             let frame = &unsafe {*(app_data.frame)};
 
-            // START - blurb to get leap finger position bounds
-            unsafe {
-                for hand_index in 0..frame.handCount {
-                    let hand = frame.hands[hand_index as usize];
-                    for finger_index in 0..hand.fingerCount {
-                        let fingertip = hand.fingers[finger_index as usize].tipPosition;
-                        if fingertip.x > finger_r { finger_r = fingertip.x}
-                        if fingertip.x < finger_l { finger_l = fingertip.x}
-                        if fingertip.y > finger_t { finger_t = fingertip.y}
-                        if fingertip.y < finger_b { finger_b = fingertip.y}
-                    }
-                }
-                //println!("{} {} {} {}", finger_t, finger_l, finger_b, finger_r);
-            }
-            // END - blurb to get leap finger position bounds
-
             let red1 = vg::Paint::color(vg::Color::rgb(200, 50, 50));
             let red2 = vg::Paint::color(vg::Color::rgb(200, 100, 100));
             let blue1 = vg::Paint::color(vg::Color::rgb(50, 50, 200));
@@ -124,7 +477,8 @@ impl View for CustomView {
 
             let bounds = cx.bounds();
             let ((l, t), (r, b)) = (bounds.top_left(), bounds.bottom_right());
-            let coord_converter = LeapCoordConverter { t, l, b, r };
+            let calibration = app_data.calibration.right;
+            let coord_converter = LeapCoordConverter { t, l, b, r, calibration };
 
             // draw intermediate nuckle center
             let mut path = vg::Path::new();
@@ -157,13 +511,48 @@ impl View for CustomView {
             }
             canvas.fill_path(&mut path, &red1);
 
-            // blue trigger border
+            // blue trigger borders, one per calibrated finger of each playing
+            // hand; bends don't have a height to show
             let mut path = vg::Path::new();
-            let (_, border_y) = coord_converter.convert(0f32, 200.0);
-            path.move_to(l, border_y);
-            path.line_to(r,  border_y);
+            for side in [Side::Left, Side::Right] {
+                let hand_calibration = app_data.calibration.hand(side);
+                if app_data.hands.role(side) != HandRole::Play || hand_calibration.detection != DetectionMode::Height {
+                    continue;
+                }
+                for finger in 0..hand_calibration.fingers.len() {
+                    let (_, border_y) = coord_converter.convert(0f32, hand_calibration.press_height(finger));
+                    path.move_to(l, border_y);
+                    path.line_to(r,  border_y);
+                }
+            }
             canvas.stroke_path(&mut path, &blue1);
 
+            // wrist zones along the top, the active one filled, with a
+            // marker at the wrist's angle
+            let wrist = &app_data.wrist;
+            let (low, high) = wrist.range();
+            if high > low {
+                let bar_height = 12.0;
+                let to_x = |angle: f32| l + (r - l) * ((angle - low) / (high - low)).max(0.0).min(1.0);
+                let mut zones = vg::Path::new();
+                let mut active = vg::Path::new();
+                for (index, zone) in wrist.zones.iter().enumerate() {
+                    let (x1, x2) = (to_x(zone.low), to_x(zone.high));
+                    zones.rect(x1, t, x2 - x1, bar_height);
+                    if wrist.active == Some(index) {
+                        active.rect(x1, t, x2 - x1, bar_height);
+                    }
+                }
+                canvas.fill_path(&mut active, &green3);
+                canvas.stroke_path(&mut zones, &green2);
+                if let Some(angle) = wrist.angle {
+                    let mut marker = vg::Path::new();
+                    marker.move_to(to_x(angle), t);
+                    marker.line_to(to_x(angle), t + bar_height * 1.5);
+                    canvas.stroke_path(&mut marker, &red1);
+                }
+            }
+
             // boundary circles -- temporary
             let mut path = vg::Path::new();
             path.rect(l, t, r-l, b-t);
@@ -171,3 +560,155 @@ impl View for CustomView {
         }
     }
 }
+
+
+// Waveform of the sample being edited with draggable trim and loop markers
+pub struct SampleEditorView {
+    dragging: Option<SampleMarker>,
+    cursor_x: f32,
+}
+
+const MARKERS: [SampleMarker; 4] = [
+    SampleMarker::Start,
+    SampleMarker::LoopStart,
+    SampleMarker::LoopEnd,
+    SampleMarker::End,
+];
+
+impl SampleEditorView {
+    pub fn new(cx: &mut Context, revision: impl Lens<Target = i32>) -> Handle<Self> {
+        Self{ dragging: None, cursor_x: 0.0 }
+          .build(cx, |_|{})
+          .bind(revision, |handle, _revision_lens| {
+            handle.cx.need_redraw()
+          })
+    }
+}
+
+// A loop over the middle half of the trimmed sample, to drag from there
+fn with_loop(points: SamplePoints) -> SamplePoints {
+    if points.loop_start.is_some() && points.loop_end.is_some() {
+        return points;
+    }
+    let quarter = (points.end - points.start) / 4;
+    SamplePoints {
+        loop_start: Some(points.start + quarter),
+        loop_end: Some(points.end - quarter),
+        ..points
+    }
+}
+
+fn sample_to_x(position: usize, len: usize, l: f32, r: f32) -> f32 {
+    l + (r - l) * position as f32 / len.max(1) as f32
+}
+
+fn x_to_sample(x: f32, len: usize, l: f32, r: f32) -> usize {
+    let fraction = ((x - l) / (r - l)).max(0.0).min(1.0);
+    (fraction * len as f32) as usize
+}
+
+impl View for SampleEditorView {
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        let bounds = cx.bounds();
+        let (l, r) = (bounds.left(), bounds.right());
+        let (len, points) = match cx.data::<AppData>() {
+            Some(app_data) => match app_data.instrument.zones.get(app_data.editor_zone) {
+                Some(zone) => (zone.sample.data().len(), app_data.editor_points),
+                None => return,
+            },
+            None => return,
+        };
+        event.map(|window_event, _| match window_event {
+            WindowEvent::MouseMove(x, _) => {
+                self.cursor_x = *x;
+                if let Some(marker) = self.dragging {
+                    cx.emit(AppEvent::MoveMarker(marker, x_to_sample(*x, len, l, r)));
+                }
+            },
+            WindowEvent::MouseDown(MouseButton::Left) => {
+                let cursor_x = self.cursor_x;
+                self.dragging = MARKERS.iter().cloned()
+                    .filter_map(|marker| marker.position(&points).map(|x| (marker, x)))
+                    .map(|(marker, position)| (marker, (sample_to_x(position, len, l, r) - cursor_x).abs()))
+                    .filter(|(_, distance)| *distance < 10.0)
+                    .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                    .map(|(marker, _)| marker);
+                if self.dragging.is_some() {
+                    cx.capture();
+                }
+            },
+            WindowEvent::MouseUp(MouseButton::Left) => {
+                if self.dragging.take().is_some() {
+                    cx.release();
+                    cx.emit(AppEvent::CommitSamplePoints);
+                }
+            },
+            _ => {}
+        });
+    }
+
+    fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
+        if let Some(app_data) = cx.data::<AppData>() {
+            let zone = match app_data.instrument.zones.get(app_data.editor_zone) {
+                Some(zone) => zone,
+                None => return,
+            };
+            let data = zone.sample.data();
+            let points = app_data.editor_points;
+
+            let grey1 = vg::Paint::color(vg::Color::rgb(200, 200, 200));
+            let blue1 = vg::Paint::color(vg::Color::rgb(50, 50, 200));
+            let blue2 = vg::Paint::color(vg::Color::rgba(50, 50, 200, 60));
+            let green1 = vg::Paint::color(vg::Color::rgb(50, 100, 50));
+            let red1 = vg::Paint::color(vg::Color::rgb(200, 50, 50));
+
+            let bounds = cx.bounds();
+            let ((l, t), (r, b)) = (bounds.top_left(), bounds.bottom_right());
+            let middle = (t + b) / 2.0;
+            let half_height = (b - t) / 2.0;
+
+            // loop region
+            if let (Some(loop_start), Some(loop_end)) = (points.loop_start, points.loop_end) {
+                let mut path = vg::Path::new();
+                let loop_l = sample_to_x(loop_start, data.len(), l, r);
+                let loop_r = sample_to_x(loop_end, data.len(), l, r);
+                path.rect(loop_l, t, loop_r - loop_l, b - t);
+                canvas.fill_path(&mut path, &blue2);
+            }
+
+            // waveform, drawn as the min/max of the samples under each pixel
+            let mut path = vg::Path::new();
+            let columns = (r - l).max(1.0) as usize;
+            let per_column = (data.len() / columns).max(1);
+            for (column, chunk) in data.chunks(per_column).enumerate() {
+                let x = l + column as f32 * (r - l) / (data.len() / per_column).max(1) as f32;
+                let max = chunk.iter().cloned().fold(f32::NEG_INFINITY, f32::max).min(1.0);
+                let min = chunk.iter().cloned().fold(f32::INFINITY, f32::min).max(-1.0);
+                path.move_to(x, middle - max * half_height);
+                path.line_to(x, middle - min * half_height);
+            }
+            canvas.stroke_path(&mut path, &grey1);
+
+            // markers
+            for marker in MARKERS {
+                let paint = match marker {
+                    SampleMarker::Start | SampleMarker::End => &green1,
+                    SampleMarker::LoopStart | SampleMarker::LoopEnd => &blue1,
+                };
+                let paint = if self.dragging == Some(marker) { &red1 } else { paint };
+                let x = match marker.position(&points) {
+                    Some(position) => sample_to_x(position, data.len(), l, r),
+                    None => continue,
+                };
+                let mut path = vg::Path::new();
+                path.move_to(x, t);
+                path.line_to(x, b);
+                canvas.stroke_path(&mut path, paint);
+            }
+
+            let mut path = vg::Path::new();
+            path.rect(l, t, r-l, b-t);
+            canvas.stroke_path(&mut path, &green1);
+        }
+    }
+}
@@ -3,10 +3,31 @@ extern crate enum_display_derive;
 extern crate cpal;
 
 
+mod arp;
+mod calibration;
+mod chord;
+mod control;
+mod effects;
+mod filter;
+mod fm;
+mod gesture;
+mod handview;
 mod leaprust;
+mod lfo;
+mod limiter;
+mod looper;
+mod looping;
 mod lrcpal;
 mod lrviz;
+mod midi;
+mod modmatrix;
+mod multisample;
 mod notefreq;
+mod preset;
+mod transport;
+mod trigger;
+mod wavetable;
+mod wrist;
 
 
 use cpal::traits::StreamTrait;
@@ -22,9 +43,23 @@ use leaprust::{
     remove_listener,
 };
 
-use lrviz::{AppData, AppEvent, CustomView};
-use lrcpal::{NoteShape, set_up_cpal};
+use arp::ArpSettings;
+use calibration::{Calibrator, HandCalibrations, PROFILE_PATH};
+use chord::ChordSettings;
+use effects::EffectSettings;
+use gesture::{GestureBindings, GestureRecognizer};
+use handview::{HandSettings, Side};
+use lrviz::{effects_panel, hand_roles, AppData, AppEvent, CustomView, SampleEditorView};
+use looper::{LoopSettings, LooperCommand};
+use lrcpal::{load_violin, NoteShape, set_up_cpal};
+use midi::{ClockMessage, TimedMessage};
+use modmatrix::ModMatrix;
+use preset::{Preset, PRESET_PATH};
 use rtrb::RingBuffer;
+use transport::{TransportCommand, TransportSettings};
+use trigger::DetectionMode;
+use wavetable::Wavetable;
+use wrist::WristSwitch;
 
 use std::mem;
 use std::time::{self, SystemTime};
@@ -36,10 +71,12 @@ use vizia::prelude::{
     HStack,
     Label,
     LayoutModifiers,
+    Lens,
     Model,
     Percentage,
     Pixels,
     RadioButton,
+    Slider,
     Stretch,
     VStack,
     WindowModifiers, EmitContext,
@@ -78,7 +115,36 @@ extern fn callback(env: *mut LeapRustEnv, frame_ptr: *mut LeapRustFrame) {
 
 fn main() {
     let frame = unsafe { blank_frame() };
-    let (mut prod, mut cons) = RingBuffer::<AppEvent>::new(5);
+    let (mut prod, mut cons) = RingBuffer::<AppEvent>::new(256);
+    let mut preset = Preset::load(PRESET_PATH);
+    let violin = load_violin(&preset);
+    let editor_violin = violin.clone();
+    let wavetable = Wavetable::from_preset(&preset).unwrap_or_else(|err| {
+        eprintln!("couldn't load wavetable, using the basic shapes: {}", err);
+        Wavetable::basic_shapes()
+    });
+    let effects = EffectSettings::from_preset(&preset);
+    let master_gain = preset.get("master.gain").unwrap_or(1.0);
+    // written back so the routes can be edited in the saved preset
+    let matrix = ModMatrix::from_preset(&preset);
+    matrix.store_preset(&mut preset);
+    let calibration = HandCalibrations::load(PROFILE_PATH);
+    let hands = HandSettings::from_preset(&preset);
+    let wrist = WristSwitch::from_preset(&preset);
+    let chords = ChordSettings::from_preset(&preset);
+    let gesture_bindings = GestureBindings::from_preset(&preset);
+    let arp = ArpSettings::from_preset(&preset);
+    let looper = LoopSettings::from_preset(&preset);
+    let transport = TransportSettings::from_preset(&preset);
+    let (clock_prod, clock_cons) = RingBuffer::<ClockMessage>::new(256);
+    // held until the app closes, dropping it stops listening
+    let _clock_in = if transport.clock_in { midi::connect_clock_in(&preset, clock_prod) } else { None };
+    let (clock_out_prod, clock_out_cons) = RingBuffer::<TimedMessage>::new(256);
+    let clock_out = if transport.clock_out { midi::connect_clock_out(&preset) } else { None };
+    let clock_out = clock_out.map(|connection| {
+        midi::spawn_clock_out(connection, clock_out_cons);
+        clock_out_prod
+    });
     /* The frame communicates 1-way from the controller to the cpal thread */
     let app = Application::new(move |cx| {
         cx.add_theme(STYLE);
@@ -88,7 +154,33 @@ fn main() {
             timestamp: 0,
             placeholder: false,
             note_shape: NoteShape::SineSquared,
-            ring_buf: prod
+            ring_buf: prod,
+            editor_points: editor_violin.zones[0].sample.points(),
+            editor_release: editor_violin.zones[0].sample.release_mode().to_string(),
+            instrument: editor_violin,
+            preset: preset,
+            editor_zone: 0,
+            editor_revision: 0,
+            auditioning: false,
+            effects: effects,
+            master_gain: master_gain,
+            calibration: calibration,
+            calibrator: Calibrator::new(calibration),
+            calibration_prompt: String::new(),
+            hands: hands,
+            hand_roles: hand_roles(&hands),
+            wrist: wrist,
+            chords: chords,
+            chord_name: chords.describe(),
+            gestures: GestureRecognizer::new(),
+            gesture_bindings: gesture_bindings,
+            sustain_key: false,
+            arp: arp,
+            arp_name: arp.describe(),
+            looper: looper,
+            looper_name: looper.describe(),
+            transport: transport,
+            transport_name: transport.describe(),
         }.build(cx);
         VStack::new(cx, |cx| {
             HStack::new(cx , |cx| {
@@ -99,9 +191,134 @@ fn main() {
             })
                 .child_space(Stretch(1.0))
                 .col_between(Pixels(4.0));
+            HStack::new(cx, |cx| {
+                Button::new(cx, |cx| cx.emit(AppEvent::StartCalibration), |cx| Label::new(cx, "Calibrate"));
+                Label::new(cx, AppData::calibration_prompt);
+                Button::new(cx, |cx| cx.emit(AppEvent::NextCalibrationStep), |cx| Label::new(cx, "Next"));
+                Button::new(cx, |cx| cx.emit(AppEvent::SetDetectionMode(DetectionMode::Height)), |cx| Label::new(cx, "Height"));
+                Button::new(cx, |cx| cx.emit(AppEvent::SetDetectionMode(DetectionMode::Flexion)), |cx| Label::new(cx, "Bend"));
+                VStack::new(cx, |cx| {
+                    Label::new(cx, "Hold");
+                    // shortest press runs up to 200ms
+                    Slider::new(cx, AppData::calibration.map(|x| x.right.min_hold as f32 / 200_000.0))
+                        .on_changing(|cx, value| cx.emit(AppEvent::SetMinHold((value * 200_000.0) as i32)));
+                })
+                    .width(Pixels(80.0));
+                VStack::new(cx, |cx| {
+                    Label::new(cx, "Speed");
+                    // slowest press runs up to 500mm/s
+                    Slider::new(cx, AppData::calibration.map(|x| x.right.min_speed / 500.0))
+                        .on_changing(|cx, value| cx.emit(AppEvent::SetMinSpeed(value * 500.0)));
+                })
+                    .width(Pixels(80.0));
+            })
+                .child_space(Stretch(1.0))
+                .col_between(Pixels(4.0));
+            HStack::new(cx, |cx| {
+                Button::new(cx, |cx| cx.emit(AppEvent::CycleHandRole(Side::Left)), |cx| Label::new(cx, "Left hand"));
+                Label::new(cx, AppData::hand_roles);
+                Button::new(cx, |cx| cx.emit(AppEvent::CycleHandRole(Side::Right)), |cx| Label::new(cx, "Right hand"));
+            })
+                .child_space(Stretch(1.0))
+                .col_between(Pixels(4.0));
+            HStack::new(cx, |cx| {
+                Button::new(cx, |cx| {
+                    let chords = cx.data::<AppData>().map_or(ChordSettings::new(), |x| x.chords);
+                    cx.emit(AppEvent::SetChords(ChordSettings { key: chords.next_key(), ..chords }))
+                }, |cx| Label::new(cx, "Key"));
+                Button::new(cx, |cx| {
+                    let chords = cx.data::<AppData>().map_or(ChordSettings::new(), |x| x.chords);
+                    cx.emit(AppEvent::SetChords(ChordSettings { scale: chords.scale.next(), ..chords }))
+                }, |cx| Label::new(cx, "Scale"));
+                Button::new(cx, |cx| {
+                    let chords = cx.data::<AppData>().map_or(ChordSettings::new(), |x| x.chords);
+                    cx.emit(AppEvent::SetChords(ChordSettings { quality: chords.quality.next(), inversion: 0, ..chords }))
+                }, |cx| Label::new(cx, "Chord"));
+                Button::new(cx, |cx| {
+                    let chords = cx.data::<AppData>().map_or(ChordSettings::new(), |x| x.chords);
+                    cx.emit(AppEvent::SetChords(ChordSettings { voicing: chords.voicing.next(), ..chords }))
+                }, |cx| Label::new(cx, "Voicing"));
+                Button::new(cx, |cx| {
+                    let chords = cx.data::<AppData>().map_or(ChordSettings::new(), |x| x.chords);
+                    cx.emit(AppEvent::SetChords(ChordSettings { inversion: chords.next_inversion(), ..chords }))
+                }, |cx| Label::new(cx, "Inversion"));
+                Label::new(cx, AppData::chord_name);
+                Button::new(cx, |cx| {
+                    let arp = cx.data::<AppData>().map_or(ArpSettings::new(), |x| x.arp);
+                    cx.emit(AppEvent::SetArp(ArpSettings { pattern: arp.pattern.next(), ..arp }))
+                }, |cx| Label::new(cx, "Arp"));
+                Button::new(cx, |cx| {
+                    let arp = cx.data::<AppData>().map_or(ArpSettings::new(), |x| x.arp);
+                    cx.emit(AppEvent::SetArp(ArpSettings { division: arp.next_division(), ..arp }))
+                }, |cx| Label::new(cx, "Rate"));
+                Label::new(cx, AppData::arp_name);
+            })
+                .child_space(Stretch(1.0))
+                .col_between(Pixels(4.0));
+            HStack::new(cx, |cx| {
+                Button::new(cx, |cx| cx.emit(AppEvent::Looper(LooperCommand::Record)), |cx| Label::new(cx, "Rec"));
+                Button::new(cx, |cx| cx.emit(AppEvent::Looper(LooperCommand::Overdub)), |cx| Label::new(cx, "Dub"));
+                Button::new(cx, |cx| cx.emit(AppEvent::Looper(LooperCommand::Play)), |cx| Label::new(cx, "Play"));
+                Button::new(cx, |cx| cx.emit(AppEvent::Looper(LooperCommand::Stop)), |cx| Label::new(cx, "Stop"));
+                Button::new(cx, |cx| cx.emit(AppEvent::Looper(LooperCommand::Undo)), |cx| Label::new(cx, "Undo"));
+                Button::new(cx, |cx| cx.emit(AppEvent::Looper(LooperCommand::Clear)), |cx| Label::new(cx, "Clear"));
+                Button::new(cx, |cx| {
+                    let looper = cx.data::<AppData>().map_or(LoopSettings::new(), |x| x.looper);
+                    cx.emit(AppEvent::SetLoopSettings(LoopSettings { bars: looper.next_bars(), ..looper }))
+                }, |cx| Label::new(cx, "Bars"));
+                Label::new(cx, AppData::looper_name);
+            })
+                .child_space(Stretch(1.0))
+                .col_between(Pixels(4.0));
+            HStack::new(cx, |cx| {
+                Button::new(cx, |cx| cx.emit(AppEvent::Transport(TransportCommand::Start)), |cx| Label::new(cx, "Start"));
+                Button::new(cx, |cx| cx.emit(AppEvent::Transport(TransportCommand::Stop)), |cx| Label::new(cx, "Halt"));
+                Button::new(cx, |cx| {
+                    let transport = cx.data::<AppData>().map_or(TransportSettings::new(), |x| x.transport);
+                    cx.emit(AppEvent::SetTransport(transport.with_bpm(transport.bpm.round() - 1.0)))
+                }, |cx| Label::new(cx, "-"));
+                Button::new(cx, |cx| {
+                    let transport = cx.data::<AppData>().map_or(TransportSettings::new(), |x| x.transport);
+                    cx.emit(AppEvent::SetTransport(transport.with_bpm(transport.bpm.round() + 1.0)))
+                }, |cx| Label::new(cx, "+"));
+                Button::new(cx, |cx| {
+                    let transport = cx.data::<AppData>().map_or(TransportSettings::new(), |x| x.transport);
+                    cx.emit(AppEvent::SetTransport(TransportSettings { beats_per_bar: transport.next_meter(), ..transport }))
+                }, |cx| Label::new(cx, "Meter"));
+                Button::new(cx, |cx| {
+                    let transport = cx.data::<AppData>().map_or(TransportSettings::new(), |x| x.transport);
+                    cx.emit(AppEvent::SetTransport(TransportSettings { metronome: !transport.metronome, ..transport }))
+                }, |cx| Label::new(cx, "Click"));
+                Label::new(cx, AppData::transport_name);
+            })
+                .child_space(Stretch(1.0))
+                .col_between(Pixels(4.0));
             CustomView::new(cx, AppData::timestamp)
                 .width(Percentage(99.0))
                 .height(Percentage(50.0));
+            HStack::new(cx, |cx| {
+                Button::new(cx, |cx| {
+                    let zone = cx.data::<AppData>().map_or(0, |x| x.editor_zone);
+                    cx.emit(AppEvent::SelectZone(zone.saturating_sub(1)))
+                }, |cx| Label::new(cx, "<"));
+                Label::new(cx, AppData::editor_zone);
+                Button::new(cx, |cx| {
+                    let zone = cx.data::<AppData>().map_or(0, |x| x.editor_zone);
+                    cx.emit(AppEvent::SelectZone(zone + 1))
+                }, |cx| Label::new(cx, ">"));
+                Button::new(cx, |cx| cx.emit(AppEvent::SetLoop), |cx| Label::new(cx, "Loop"));
+                Button::new(cx, |cx| cx.emit(AppEvent::ClearLoop), |cx| Label::new(cx, "No loop"));
+                Button::new(cx, |cx| cx.emit(AppEvent::ToggleReleaseMode), |cx| Label::new(cx, "Release"));
+                Label::new(cx, AppData::editor_release);
+                Button::new(cx, |cx| cx.emit(AppEvent::ToggleAudition), |cx| Label::new(cx, "Audition"));
+                Button::new(cx, |cx| cx.emit(AppEvent::SavePreset), |cx| Label::new(cx, "Save"));
+            })
+                .child_space(Stretch(1.0))
+                .col_between(Pixels(4.0));
+            SampleEditorView::new(cx, AppData::editor_revision)
+                .width(Percentage(99.0))
+                .height(Percentage(25.0));
+            effects_panel(cx);
         })
         .child_space(Stretch(1.0))
         .col_between(Pixels(50.0));
@@ -119,7 +336,7 @@ fn main() {
         controller = get_controller(&mut env, Some(callback));
         add_listener(controller);
     }
-    let stream = set_up_cpal(frame, cons);
+    let stream = set_up_cpal(frame, cons, violin, wavetable, effects, master_gain, matrix, calibration, hands, chords, arp, looper, transport, clock_cons, clock_out);
 
     app.run();
 
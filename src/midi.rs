@@ -0,0 +1,131 @@
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use rtrb::{Consumer, Producer};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::preset::Preset;
+
+pub const CLOCK: u8 = 0xF8;
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+// how often the clock out thread looks for new messages
+const CLOCK_OUT_POLL: Duration = Duration::from_micros(500);
+
+// Real-time messages from an incoming MIDI clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockMessage {
+    // timestamped in microseconds
+    Tick(u64),
+    Start,
+    // start again from the current position
+    Continue,
+    Stop,
+}
+
+// A message from the audio thread, to go out when the sample it fell on
+// is heard
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimedMessage {
+    pub at: Instant,
+    pub message: u8,
+}
+
+// Ports are picked by part of their name, set in midi.clock_in_port and
+// midi.clock_out_port. The first port would usually be a loopback that
+// hears our own clock out.
+fn find_port(preset: &Preset, key: &str, names: &[String]) -> Option<usize> {
+    let wanted: Option<String> = preset.get(key);
+    let index = wanted.as_ref().and_then(|wanted| names.iter().position(|x| x.contains(wanted.as_str())));
+    if index.is_none() {
+        eprintln!("set {} to one of {:?}", key, names);
+    }
+    index
+}
+
+// Listens to the configured MIDI input for clock, passing it on to the
+// audio thread through its own ring buffer. Keep the connection around for
+// as long as it should listen.
+pub fn connect_clock_in(preset: &Preset, mut clock: Producer<ClockMessage>) -> Option<MidiInputConnection<()>> {
+    let mut input = match MidiInput::new("leapsynth clock in") {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("no MIDI input: {}", err);
+            return None;
+        }
+    };
+    // clock is a timing message, which midir can drop
+    input.ignore(Ignore::None);
+    let ports = input.ports();
+    let names: Vec<String> = ports.iter().map(|x| input.port_name(x).unwrap_or_default()).collect();
+    let index = find_port(preset, "midi.clock_in_port", &names)?;
+    let (port, name) = (&ports[index], &names[index]);
+    let connection = input.connect(port, "clock in", move |timestamp, message, _| {
+        let message = match message.first() {
+            Some(&CLOCK) => ClockMessage::Tick(timestamp),
+            Some(&START) => ClockMessage::Start,
+            Some(&CONTINUE) => ClockMessage::Continue,
+            Some(&STOP) => ClockMessage::Stop,
+            _ => return,
+        };
+        // nothing to do about a full buffer from here but drop the pulse
+        let _ = clock.push(message);
+    }, ());
+    match connection {
+        Ok(connection) => {
+            println!("following MIDI clock from {}", name);
+            Some(connection)
+        },
+        Err(err) => {
+            eprintln!("couldn't connect to MIDI input {}: {}", name, err);
+            None
+        }
+    }
+}
+
+// The configured MIDI output, for sending clock to
+pub fn connect_clock_out(preset: &Preset) -> Option<MidiOutputConnection> {
+    let output = match MidiOutput::new("leapsynth clock out") {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("no MIDI output: {}", err);
+            return None;
+        }
+    };
+    let ports = output.ports();
+    let names: Vec<String> = ports.iter().map(|x| output.port_name(x).unwrap_or_default()).collect();
+    let index = find_port(preset, "midi.clock_out_port", &names)?;
+    let (port, name) = (&ports[index], &names[index]);
+    match output.connect(port, "clock out") {
+        Ok(connection) => {
+            println!("sending MIDI clock to {}", name);
+            Some(connection)
+        },
+        Err(err) => {
+            eprintln!("couldn't connect to MIDI output {}: {}", name, err);
+            None
+        }
+    }
+}
+
+// Sends the audio thread's messages at their time, keeping the port's
+// writes off the audio thread. Stops once the audio thread lets go.
+pub fn spawn_clock_out(mut connection: MidiOutputConnection, mut messages: Consumer<TimedMessage>) {
+    thread::spawn(move || {
+        loop {
+            match messages.pop() {
+                Ok(timed) => {
+                    let now = Instant::now();
+                    if timed.at > now {
+                        thread::sleep(timed.at - now);
+                    }
+                    if let Err(err) = connection.send(&[timed.message]) {
+                        eprintln!("couldn't send MIDI clock: {}", err);
+                    }
+                },
+                Err(_) if messages.is_abandoned() => break,
+                Err(_) => thread::sleep(CLOCK_OUT_POLL),
+            }
+        }
+    });
+}
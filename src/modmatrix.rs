@@ -0,0 +1,288 @@
+use std::fmt;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::calibration::Calibration;
+use crate::control::ControlSource;
+use crate::leaprust::{LeapRustFinger, LeapRustHand};
+use crate::preset::Preset;
+
+// Where a route's output goes. Pitch is in semitones, volume a gain
+// multiplier, cutoff in octaves, pan from -1 (left) to 1 (right), timbre
+// 0..1 and the sends are added to the bus effects' mix.
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum ModTarget {
+    Pitch,
+    Volume,
+    Cutoff,
+    Pan,
+    Timbre,
+    ReverbSend,
+    DelaySend,
+    ChorusSend,
+}
+
+impl ModTarget {
+    pub const ALL: [ModTarget; 8] = [
+        ModTarget::Pitch,
+        ModTarget::Volume,
+        ModTarget::Cutoff,
+        ModTarget::Pan,
+        ModTarget::Timbre,
+        ModTarget::ReverbSend,
+        ModTarget::DelaySend,
+        ModTarget::ChorusSend,
+    ];
+}
+
+impl FromStr for ModTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ModTarget, String> {
+        ModTarget::ALL.iter()
+            .find(|x| x.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown modulation target {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum ModCurve {
+    Linear,
+    // slow start, fast finish
+    Exponential,
+    // fast start, slow finish
+    Logarithmic,
+    // slow at both ends
+    SCurve,
+}
+
+impl ModCurve {
+    pub const ALL: [ModCurve; 4] = [
+        ModCurve::Linear,
+        ModCurve::Exponential,
+        ModCurve::Logarithmic,
+        ModCurve::SCurve,
+    ];
+
+    fn apply(&self, x: f32) -> f32 {
+        match self {
+            ModCurve::Linear => x,
+            ModCurve::Exponential => x * x,
+            ModCurve::Logarithmic => x.sqrt(),
+            ModCurve::SCurve => x * x * (3.0 - 2.0 * x),
+        }
+    }
+}
+
+impl FromStr for ModCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ModCurve, String> {
+        ModCurve::ALL.iter()
+            .find(|x| x.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown modulation curve {}", s))
+    }
+}
+
+// One source to one target. The source's 0..1 reading goes through the
+// curve and is scaled from low to high. Relative routes only count the
+// change since the note started, so the note starts unmodulated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModRoute {
+    pub source: ControlSource,
+    pub target: ModTarget,
+    pub low: f32,
+    pub high: f32,
+    pub curve: ModCurve,
+    // 0 follows the hand immediately, closer to 1 glides more
+    pub smoothing: f32,
+    pub relative: bool,
+}
+
+impl ModRoute {
+    pub fn new(source: ControlSource, target: ModTarget, low: f32, high: f32) -> ModRoute {
+        ModRoute {
+            source: source,
+            target: target,
+            low: low,
+            high: high,
+            curve: ModCurve::Linear,
+            smoothing: 0.0,
+            relative: false,
+        }
+    }
+
+    pub fn with_curve(mut self, curve: ModCurve) -> ModRoute {
+        self.curve = curve;
+        self
+    }
+
+    pub fn with_smoothing(mut self, smoothing: f32) -> ModRoute {
+        self.smoothing = smoothing.max(0.0).min(0.99);
+        self
+    }
+
+    pub fn relative(mut self) -> ModRoute {
+        self.relative = true;
+        self
+    }
+
+    fn scale(&self, value: f32) -> f32 {
+        self.low + (self.high - self.low) * self.curve.apply(value)
+    }
+}
+
+// Written to presets as "source target low high curve smoothing [relative]"
+impl fmt::Display for ModRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} {} {} {}", self.source, self.target, self.low, self.high, self.curve, self.smoothing)?;
+        if self.relative {
+            write!(f, " relative")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ModRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ModRoute, String> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() < 4 {
+            return Err(format!("modulation route needs a source, target, low and high: {}", s));
+        }
+        let number = |x: &str| x.parse::<f32>().map_err(|_| format!("bad number {} in {}", x, s));
+        let mut route = ModRoute::new(parts[0].parse()?, parts[1].parse()?, number(parts[2])?, number(parts[3])?);
+        if let Some(curve) = parts.get(4) {
+            route = route.with_curve(curve.parse()?);
+        }
+        if let Some(smoothing) = parts.get(5) {
+            route = route.with_smoothing(number(smoothing)?);
+        }
+        if parts.get(6) == Some(&"relative") {
+            route = route.relative();
+        }
+        Ok(route)
+    }
+}
+
+// Summed output of every route for one voice
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModValues {
+    pub pitch: f32,
+    pub volume: f32,
+    pub cutoff: f32,
+    pub pan: f32,
+    pub timbre: Option<f32>,
+    pub reverb_send: f32,
+    pub delay_send: f32,
+    pub chorus_send: f32,
+}
+
+impl ModValues {
+    pub fn new() -> ModValues {
+        ModValues {
+            pitch: 0.0,
+            volume: 1.0,
+            cutoff: 0.0,
+            pan: 0.0,
+            timbre: None,
+            reverb_send: 0.0,
+            delay_send: 0.0,
+            chorus_send: 0.0,
+        }
+    }
+
+    fn add(&mut self, target: ModTarget, value: f32) {
+        match target {
+            ModTarget::Pitch => self.pitch += value,
+            ModTarget::Volume => self.volume *= value.max(0.0),
+            ModTarget::Cutoff => self.cutoff += value,
+            ModTarget::Pan => self.pan = (self.pan + value).max(-1.0).min(1.0),
+            ModTarget::Timbre => self.timbre = Some(self.timbre.unwrap_or(0.0) + value),
+            ModTarget::ReverbSend => self.reverb_send += value,
+            ModTarget::DelaySend => self.delay_send += value,
+            ModTarget::ChorusSend => self.chorus_send += value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModMatrix {
+    pub routes: Vec<ModRoute>,
+}
+
+impl ModMatrix {
+    // Sliding the fingertip sideways bends the note, as it always has
+    pub fn new() -> ModMatrix {
+        ModMatrix {
+            routes: vec!(
+                ModRoute::new(ControlSource::FingerX, ModTarget::Pitch, -3.5, 3.5).relative(),
+            ),
+        }
+    }
+
+    // Routes are stored as mod.0, mod.1, ... and read until the first gap
+    pub fn from_preset(preset: &Preset) -> ModMatrix {
+        let mut routes = Vec::new();
+        while let Some(line) = preset.get::<String>(&format!("mod.{}", routes.len())) {
+            match line.parse() {
+                Ok(route) => routes.push(route),
+                Err(err) => {
+                    println!("Ignoring {}", err);
+                    break;
+                }
+            }
+        }
+        if routes.is_empty() {
+            return ModMatrix::new();
+        }
+        ModMatrix { routes: routes }
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset) {
+        for (index, route) in self.routes.iter().enumerate() {
+            preset.set(&format!("mod.{}", index), route);
+        }
+    }
+}
+
+// Smoothing and note-on readings of the routes for one voice
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModState {
+    smoothed: Vec<Option<f32>>,
+    start: Vec<Option<f32>>,
+}
+
+impl ModState {
+    pub fn new() -> ModState {
+        ModState { smoothed: Vec::new(), start: Vec::new() }
+    }
+
+    pub fn update(&mut self, matrix: &ModMatrix, hand: &LeapRustHand, finger: &LeapRustFinger, calibration: &Calibration) -> ModValues {
+        self.smoothed.resize(matrix.routes.len(), None);
+        self.start.resize(matrix.routes.len(), None);
+        let mut values = ModValues::new();
+        for (index, route) in matrix.routes.iter().enumerate() {
+            let reading = match route.source.read(hand, finger, calibration) {
+                Some(reading) => route.scale(reading),
+                None => continue,
+            };
+            let smoothed = match self.smoothed[index] {
+                Some(last) => last + (reading - last) * (1.0 - route.smoothing),
+                None => reading,
+            };
+            self.smoothed[index] = Some(smoothed);
+            let start = *self.start[index].get_or_insert(smoothed);
+            let value = match (route.relative, route.target) {
+                (false, _) => smoothed,
+                (true, ModTarget::Volume) => if start != 0.0 { smoothed / start } else { 1.0 },
+                (true, _) => smoothed - start,
+            };
+            values.add(route.target, value);
+        }
+        values
+    }
+}
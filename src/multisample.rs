@@ -0,0 +1,242 @@
+use std::path::Path;
+
+use crate::looping;
+use crate::lrcpal::{file_to_sample, PlaybackSample, ReleaseMode, SamplePoints};
+use crate::notefreq;
+use crate::preset::Preset;
+
+pub const DEFAULT_VELOCITY: u8 = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleZone {
+    // file stem the zone was loaded from, for declaring its ranges in a preset
+    pub source: String,
+    pub root_note: u8,
+    pub low_key: u8,
+    pub high_key: u8,
+    pub low_velocity: u8,
+    pub high_velocity: u8,
+    pub sample: PlaybackSample,
+}
+
+impl SampleZone {
+    pub fn new(sample: PlaybackSample, root_note: u8) -> SampleZone {
+        SampleZone {
+            source: String::new(),
+            root_note: root_note,
+            low_key: 0,
+            high_key: 127,
+            low_velocity: 0,
+            high_velocity: 127,
+            sample: sample,
+        }
+    }
+
+    pub fn with_velocities(mut self, low_velocity: u8, high_velocity: u8) -> SampleZone {
+        self.low_velocity = low_velocity;
+        self.high_velocity = high_velocity;
+        self
+    }
+
+    fn contains_key(&self, key: u8) -> bool {
+        self.low_key <= key && key <= self.high_key
+    }
+
+    fn contains_velocity(&self, velocity: u8) -> bool {
+        self.low_velocity <= velocity && velocity <= self.high_velocity
+    }
+
+    fn distance(&self, note: f32) -> f32 {
+        (note - self.root_note as f32).abs()
+    }
+
+    // Zones are stored as <name>.<root note>.<low>-<high velocity>.<marker>
+    // so velocity layers on the same root keep their own settings
+    fn preset_key(&self, name: &str, marker: &str) -> String {
+        format!("{}.{}.{}-{}.{}", name, self.root_note, self.low_velocity, self.high_velocity, marker)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Multisample {
+    pub zones: Vec<SampleZone>,
+}
+
+impl Multisample {
+    pub fn new() -> Multisample {
+        Multisample { zones: Vec::new() }
+    }
+
+    pub fn add_zone(&mut self, zone: SampleZone) {
+        self.zones.push(zone);
+    }
+
+    // Loads one zone per file, taking the root note from a note name in the
+    // file name (e.g. violin_Cs5_1_fortissimo_arco-normal.mp3) or the WAV
+    // sampler chunk, and splitting the keyboard halfway between neighbouring
+    // roots. A dynamic in the file name picks the velocity layer.
+    pub fn from_files(paths: &[&str]) -> Multisample {
+        let mut multisample = Multisample::new();
+        for path in paths {
+            let root_note = root_note_from_path(path)
+                .or_else(|| looping::read_sampler_chunk(Path::new(path)).map(|x| x.unity_note))
+                .expect(&format!("Couldn't find a note name in {}", path));
+            let (low_velocity, high_velocity) = velocities_from_path(path).unwrap_or((0, 127));
+            let mut zone = SampleZone::new(file_to_sample(path), root_note)
+                .with_velocities(low_velocity, high_velocity);
+            zone.source = Path::new(path).file_stem().and_then(|x| x.to_str()).unwrap_or_default().to_string();
+            multisample.add_zone(zone);
+        }
+        multisample.spread_key_ranges();
+        multisample.spread_velocity_ranges();
+        multisample
+    }
+
+    pub fn set_release_mode(&mut self, release_mode: ReleaseMode) {
+        for zone in &mut self.zones {
+            zone.sample.set_release_mode(release_mode);
+        }
+    }
+
+    // Velocity layers share a root, so the split is between distinct roots
+    pub fn spread_key_ranges(&mut self) {
+        self.zones.sort_by_key(|zone| (zone.root_note, zone.low_velocity));
+        let mut roots: Vec<u8> = self.zones.iter().map(|zone| zone.root_note).collect();
+        roots.dedup();
+        for zone in &mut self.zones {
+            let index = roots.iter().position(|x| *x == zone.root_note).unwrap_or(0);
+            let (low_key, high_key) = key_range(&roots, index);
+            zone.low_key = low_key;
+            zone.high_key = high_key;
+        }
+    }
+
+    // Widens each root's velocity layers to meet halfway across any gaps
+    // between them and reach 0 and 127, so every velocity has a layer.
+    // Expects the zones sorted by root then velocity.
+    pub fn spread_velocity_ranges(&mut self) {
+        for index in 0..self.zones.len() {
+            let root = self.zones[index].root_note;
+            if index == 0 || self.zones[index - 1].root_note != root {
+                self.zones[index].low_velocity = 0;
+            }
+            match self.zones.get(index + 1).filter(|next| next.root_note == root).map(|next| next.low_velocity) {
+                Some(next_low) => {
+                    let high = self.zones[index].high_velocity.min(next_low.saturating_sub(1));
+                    let split = (high + next_low) / 2;
+                    self.zones[index].high_velocity = split;
+                    self.zones[index + 1].low_velocity = split + 1;
+                },
+                None => self.zones[index].high_velocity = 127,
+            }
+        }
+    }
+
+    // Prefers a zone covering both key and velocity, then any zone in the
+    // velocity layer, then whatever zone has the closest root note.
+    pub fn zone_for(&self, freq: f32, velocity: u8) -> Option<&SampleZone> {
+        let note = notefreq::freq_to_midi(freq);
+        let key = note.round().max(0.0).min(127.0) as u8;
+        nearest_zone(self.zones.iter().filter(|z| z.contains_key(key) && z.contains_velocity(velocity)), note)
+            .or_else(|| nearest_zone(self.zones.iter().filter(|z| z.contains_velocity(velocity)), note))
+            .or_else(|| nearest_zone(self.zones.iter(), note))
+    }
+
+    pub fn sample_for(&self, freq: f32, velocity: u8) -> Option<PlaybackSample> {
+        self.zone_for(freq, velocity).map(|zone| {
+            zone.sample.repitched(notefreq::midi_to_freq(zone.root_note as f32), freq)
+        })
+    }
+
+    // Key and velocity ranges can be declared per file as
+    // <name>.zone.<file stem>.keys and .velocities = "<low> <high>". The
+    // rest is keyed by root and velocity so it survives changes to the file
+    // list order.
+    pub fn apply_preset(&mut self, preset: &Preset, name: &str) {
+        for zone in &mut self.zones {
+            let range = |kind: &str| preset.get::<String>(&format!("{}.zone.{}.{}", name, zone.source, kind))
+                .and_then(|x| parse_range(&x));
+            if let Some((low, high)) = range("keys") {
+                zone.low_key = low;
+                zone.high_key = high;
+            }
+            if let Some((low, high)) = range("velocities") {
+                zone.low_velocity = low;
+                zone.high_velocity = high;
+            }
+        }
+        for zone in &mut self.zones {
+            let key = |marker: &str| zone.preset_key(name, marker);
+            let current = zone.sample.points();
+            let points = SamplePoints {
+                start: preset.get(&key("start")).unwrap_or(current.start),
+                loop_start: loop_marker(preset, &key("loop_start"), current.loop_start),
+                loop_end: loop_marker(preset, &key("loop_end"), current.loop_end),
+                end: preset.get(&key("end")).unwrap_or(current.end),
+            };
+            let release_mode = preset.get(&key("release")).unwrap_or(zone.sample.release_mode());
+            if points != current {
+                zone.sample.set_points(points);
+            }
+            zone.sample.set_release_mode(release_mode);
+        }
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset, name: &str) {
+        for zone in &self.zones {
+            let key = |marker: &str| zone.preset_key(name, marker);
+            let points = zone.sample.points();
+            preset.set(&key("start"), points.start);
+            preset.set(&key("loop_start"), points.loop_start.map_or("none".to_string(), |x| x.to_string()));
+            preset.set(&key("loop_end"), points.loop_end.map_or("none".to_string(), |x| x.to_string()));
+            preset.set(&key("end"), points.end);
+            preset.set(&key("release"), zone.sample.release_mode());
+        }
+    }
+}
+
+fn nearest_zone<'a>(zones: impl Iterator<Item = &'a SampleZone>, note: f32) -> Option<&'a SampleZone> {
+    zones.min_by(|a, b| a.distance(note).total_cmp(&b.distance(note)))
+}
+
+fn root_note_from_path(path: &str) -> Option<u8> {
+    let stem = Path::new(path).file_stem()?.to_str()?;
+    stem.split(|c| c == '_' || c == '-' || c == ' ')
+        .find_map(notefreq::parse_note_name)
+}
+
+// Halfway between neighbouring roots, with the outer zones reaching the ends
+fn key_range(roots: &[u8], index: usize) -> (u8, u8) {
+    let low = if index == 0 { 0 } else { (roots[index - 1] + roots[index]) / 2 + 1 };
+    let high = if index + 1 == roots.len() { 127 } else { (roots[index] + roots[index + 1]) / 2 };
+    (low, high)
+}
+
+// A loop marker is a sample frame, or "none" for a sample that doesn't loop
+fn loop_marker(preset: &Preset, key: &str, current: Option<usize>) -> Option<usize> {
+    match preset.get::<String>(key) {
+        Some(value) if value == "none" => None,
+        Some(value) => value.parse().ok().or(current),
+        None => current,
+    }
+}
+
+fn parse_range(value: &str) -> Option<(u8, u8)> {
+    let mut parts = value.split_whitespace().map(|x| x.parse::<u8>());
+    let low = parts.next()?.ok()?;
+    let high = parts.next()?.ok()?;
+    Some((low.min(high), low.max(high).min(127)))
+}
+
+// Velocity layer from a dynamic marking in the file name
+fn velocities_from_path(path: &str) -> Option<(u8, u8)> {
+    let stem = Path::new(path).file_stem()?.to_str()?;
+    stem.split(|c| c == '_' || c == '-' || c == ' ')
+        .find_map(|word| match word.to_ascii_lowercase().as_str() {
+            "pianissimo" | "pp" => Some((0, 31)),
+            "piano" => Some((32, 63)),
+            "forte" => Some((64, 95)),
+            "fortissimo" | "ff" => Some((96, 127)),
+            _ => None,
+        })
+}
@@ -107,3 +107,44 @@ pub static Gs8: f32 = 6644.88;
 pub static A_8: f32 = 7040.00;
 pub static As8: f32 = 7458.62;
 pub static B_8: f32 = 7902.13;
+
+pub fn midi_to_freq(note: f32) -> f32 {
+    A_4 * 2f32.powf((note - 69.0) / 12.0)
+}
+
+pub fn freq_to_midi(freq: f32) -> f32 {
+    69.0 + 12.0 * (freq / A_4).log2()
+}
+
+// Parses names like "A4", "Cs5", "C_4", "F#3" or "Bb2" into a midi note number
+pub fn parse_note_name(name: &str) -> Option<u8> {
+    let mut chars = name.chars().peekable();
+    let pitch_class: i32 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let accidental = match chars.peek() {
+        Some('s') | Some('#') => 1,
+        Some('b') => -1,
+        _ => 0,
+    };
+    if accidental != 0 {
+        chars.next();
+    }
+    if chars.peek() == Some(&'_') {
+        chars.next();
+    }
+    let octave: i32 = chars.collect::<String>().parse().ok()?;
+    let note = (octave + 1) * 12 + pitch_class + accidental;
+    if (0..=127).contains(&note) {
+        Some(note as u8)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const PRESET_PATH: &str = "leapsynth.preset";
+
+// Flat "key = value" settings file, one setting per line. Lines starting
+// with '#' are comments.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Preset {
+    values: BTreeMap<String, String>,
+}
+
+impl Preset {
+    pub fn new() -> Preset {
+        Preset { values: BTreeMap::new() }
+    }
+
+    pub fn load(path: &str) -> Preset {
+        let mut preset = Preset::new();
+        let contents = match fs::read_to_string(Path::new(path)) {
+            Ok(contents) => contents,
+            Err(err) => {
+                println!("No preset loaded from {}: {}", path, err);
+                return preset;
+            }
+        };
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once('=') {
+                preset.values.insert(key.trim().to_string(), value.trim().to_string());
+            } else {
+                println!("Ignoring preset line {:?}", line);
+            }
+        }
+        preset
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut contents = String::new();
+        for (key, value) in &self.values {
+            contents.push_str(&format!("{} = {}\n", key, value));
+        }
+        fs::write(Path::new(path), contents)
+    }
+
+    pub fn get<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.values.get(key).and_then(|x| x.parse().ok())
+    }
+
+    pub fn set<T: Display>(&mut self, key: &str, value: T) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+}
@@ -0,0 +1,216 @@
+use std::f32::consts::PI;
+
+use crate::preset::Preset;
+
+// MIDI clock pulses per quarter note
+pub const CLOCKS_PER_BEAT: u32 = 24;
+// how much of each new reading an incoming clock's tempo takes on
+const CLOCK_IN_SMOOTHING: f64 = 0.1;
+// longer gaps than this between incoming pulses mean the clock stopped
+const CLOCK_IN_TIMEOUT: u64 = 500_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportCommand {
+    Start,
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransportSettings {
+    pub bpm: f32,
+    pub beats_per_bar: u32,
+    pub metronome: bool,
+    pub metronome_gain: f32,
+    // send MIDI clock, start and stop out
+    pub clock_out: bool,
+    // follow MIDI clock coming in
+    pub clock_in: bool,
+}
+
+impl TransportSettings {
+    pub fn new() -> TransportSettings {
+        TransportSettings {
+            bpm: 120.0,
+            beats_per_bar: 4,
+            metronome: false,
+            metronome_gain: 0.2,
+            clock_out: false,
+            clock_in: false,
+        }
+    }
+
+    pub fn with_bpm(mut self, bpm: f32) -> TransportSettings {
+        self.bpm = bpm.max(40.0).min(240.0);
+        self
+    }
+
+    pub fn next_meter(&self) -> u32 {
+        if self.beats_per_bar >= 7 { 2 } else { self.beats_per_bar + 1 }
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{:.1} bpm {}/4{}", self.bpm, self.beats_per_bar, if self.metronome { " click" } else { "" })
+    }
+
+    pub fn from_preset(preset: &Preset) -> TransportSettings {
+        let defaults = TransportSettings::new();
+        TransportSettings {
+            bpm: defaults.with_bpm(preset.get("transport.bpm").unwrap_or(defaults.bpm)).bpm,
+            beats_per_bar: preset.get("transport.beats_per_bar").unwrap_or(defaults.beats_per_bar).max(1),
+            metronome: preset.get("transport.metronome").unwrap_or(defaults.metronome),
+            metronome_gain: preset.get("transport.metronome_gain").unwrap_or(defaults.metronome_gain),
+            clock_out: preset.get("transport.clock_out").unwrap_or(defaults.clock_out),
+            clock_in: preset.get("transport.clock_in").unwrap_or(defaults.clock_in),
+        }
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset) {
+        preset.set("transport.bpm", self.bpm);
+        preset.set("transport.beats_per_bar", self.beats_per_bar);
+        preset.set("transport.metronome", self.metronome);
+        preset.set("transport.metronome_gain", self.metronome_gain);
+        preset.set("transport.clock_out", self.clock_out);
+        preset.set("transport.clock_in", self.clock_in);
+    }
+}
+
+// What happened on one sample of the transport
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransportTick {
+    // a MIDI clock pulse falls on this sample
+    pub clock: bool,
+    // the beat of the bar starting on this sample, 0 is the downbeat
+    pub beat: Option<u32>,
+}
+
+impl TransportTick {
+    fn none() -> TransportTick {
+        TransportTick { clock: false, beat: None }
+    }
+}
+
+// Counts clock pulses, beats and bars, either from the sample clock or
+// from MIDI clock coming in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transport {
+    pub settings: TransportSettings,
+    pub playing: bool,
+    // following an incoming clock rather than counting samples
+    pub external: bool,
+    samples_to_clock: f64,
+    clock: u32,
+    beat: u32,
+    last_clock_in: Option<u64>,
+}
+
+impl Transport {
+    pub fn new(settings: TransportSettings) -> Transport {
+        Transport {
+            settings: settings,
+            playing: false,
+            external: false,
+            samples_to_clock: 0.0,
+            clock: 0,
+            beat: 0,
+            last_clock_in: None,
+        }
+    }
+
+    pub fn start(&mut self, external: bool) {
+        self.playing = true;
+        self.external = external;
+        self.samples_to_clock = 0.0;
+        self.clock = 0;
+        self.beat = 0;
+        self.last_clock_in = None;
+    }
+
+    // Picks up from where the transport stopped, for MIDI continue
+    pub fn resume(&mut self, external: bool) {
+        self.playing = true;
+        self.external = external;
+        // the gap while stopped isn't a tempo
+        self.last_clock_in = None;
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    fn clock_length(&self, sample_rate: u32) -> f64 {
+        sample_rate as f64 * 60.0 / (self.settings.bpm as f64 * CLOCKS_PER_BEAT as f64)
+    }
+
+    fn pulse(&mut self) -> TransportTick {
+        let beat = if self.clock == 0 { Some(self.beat) } else { None };
+        self.clock += 1;
+        if self.clock >= CLOCKS_PER_BEAT {
+            self.clock = 0;
+            self.beat = (self.beat + 1) % self.settings.beats_per_bar.max(1);
+        }
+        TransportTick { clock: true, beat: beat }
+    }
+
+    // Call once per sample
+    pub fn tick(&mut self, sample_rate: u32) -> TransportTick {
+        if !self.playing || self.external {
+            return TransportTick::none();
+        }
+        self.samples_to_clock -= 1.0;
+        if self.samples_to_clock > 0.0 {
+            return TransportTick::none();
+        }
+        self.samples_to_clock += self.clock_length(sample_rate);
+        self.pulse()
+    }
+
+    // A pulse of incoming MIDI clock, timestamped in microseconds. The
+    // tempo follows the gaps between pulses.
+    pub fn clock_in(&mut self, timestamp: u64) -> TransportTick {
+        if !self.playing || !self.external {
+            return TransportTick::none();
+        }
+        if let Some(last) = self.last_clock_in {
+            let gap = timestamp.saturating_sub(last);
+            if gap > 0 && gap < CLOCK_IN_TIMEOUT {
+                let bpm = 60_000_000.0 / (gap as f64 * CLOCKS_PER_BEAT as f64);
+                let smoothed = self.settings.bpm as f64 + (bpm - self.settings.bpm as f64) * CLOCK_IN_SMOOTHING;
+                self.settings = self.settings.with_bpm(smoothed as f32);
+            }
+        }
+        self.last_clock_in = Some(timestamp);
+        self.pulse()
+    }
+}
+
+// Short sine blips on each beat, higher on the downbeat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metronome {
+    freq: f32,
+    phase: f32,
+    remaining: f32,
+    length: f32,
+}
+
+impl Metronome {
+    pub fn new() -> Metronome {
+        Metronome { freq: 0.0, phase: 0.0, remaining: 0.0, length: 1.0 }
+    }
+
+    pub fn click(&mut self, downbeat: bool, sample_rate: u32) {
+        self.freq = if downbeat { 1500.0 } else { 1000.0 };
+        self.phase = 0.0;
+        self.length = sample_rate as f32 * 0.03;
+        self.remaining = self.length;
+    }
+
+    pub fn next(&mut self, sample_rate: u32) -> f32 {
+        if self.remaining <= 0.0 {
+            return 0.0;
+        }
+        let val = (2.0 * PI * self.phase).sin() * self.remaining / self.length;
+        self.phase = (self.phase + self.freq / sample_rate as f32) % 1.0;
+        self.remaining -= 1.0;
+        val
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::leaprust::{LeapRustFinger, LeapRustVector};
+
+// Downward fingertip speed, in mm/s, of the softest and hardest presses
+const SOFT_PRESS_SPEED: f32 = 50.0;
+const HARD_PRESS_SPEED: f32 = 1000.0;
+
+// How hard the finger is moving down, 0..1
+pub fn press_velocity(finger: &LeapRustFinger) -> f32 {
+    let speed = -finger.tipVelocity.y;
+    let velocity = ((speed - SOFT_PRESS_SPEED) / (HARD_PRESS_SPEED - SOFT_PRESS_SPEED)).max(0.0).min(1.0);
+    // most presses are gentle, so spread out the low end
+    velocity.sqrt()
+}
+
+fn angle_between(a: LeapRustVector, b: LeapRustVector) -> f32 {
+    let dot = a.x * b.x + a.y * b.y + a.z * b.z;
+    let lengths = (a.x * a.x + a.y * a.y + a.z * a.z).sqrt() * (b.x * b.x + b.y * b.y + b.z * b.z).sqrt();
+    // the thumb's metacarpal has no length
+    if lengths == 0.0 {
+        return 0.0;
+    }
+    (dot / lengths).max(-1.0).min(1.0).acos()
+}
+
+// How far the finger is curled, in radians: the bend at each joint from the
+// metacarpal in the palm out to the tip. Doesn't change with hand height.
+pub fn finger_flexion(finger: &LeapRustFinger) -> f32 {
+    finger.bones.windows(2)
+        .map(|pair| angle_between(pair[0].direction, pair[1].direction))
+        .sum()
+}
+
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum DetectionMode {
+    // fingertip below a height above the controller
+    Height,
+    // finger curled past an angle, wherever the hand is
+    Flexion,
+}
+
+impl FromStr for DetectionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DetectionMode, String> {
+        match s {
+            "Height" => Ok(DetectionMode::Height),
+            "Flexion" => Ok(DetectionMode::Flexion),
+            _ => Err(format!("unknown detection mode {}", s)),
+        }
+    }
+}
+
+// Heights in millimetres above the controller, angles in radians and times
+// in microseconds to match the frame timestamps. The finger has to come
+// back past the release point before it lets go, so hovering around the
+// press point doesn't chatter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TriggerSettings {
+    pub mode: DetectionMode,
+    pub press_height: f32,
+    pub release_height: f32,
+    pub press_angle: f32,
+    pub release_angle: f32,
+    pub min_hold: i32,
+    // downward speed in mm/s a press needs, 0 lets slow drifts trigger too
+    pub min_speed: f32,
+}
+
+impl TriggerSettings {
+    pub fn new(press_height: f32, release_height: f32) -> TriggerSettings {
+        TriggerSettings {
+            mode: DetectionMode::Height,
+            press_height: press_height,
+            release_height: release_height.max(press_height),
+            press_angle: 1.2,
+            release_angle: 0.9,
+            min_hold: 50_000,
+            min_speed: 0.0,
+        }
+    }
+
+    pub fn with_flexion(mut self, press_angle: f32, release_angle: f32) -> TriggerSettings {
+        self.mode = DetectionMode::Flexion;
+        self.press_angle = press_angle;
+        self.release_angle = release_angle.min(press_angle);
+        self
+    }
+
+    pub fn with_min_hold(mut self, micros: i32) -> TriggerSettings {
+        self.min_hold = micros;
+        self
+    }
+
+    pub fn with_min_speed(mut self, speed: f32) -> TriggerSettings {
+        self.min_speed = speed;
+        self
+    }
+
+    pub fn is_pressed(&self, finger: &LeapRustFinger) -> bool {
+        match self.mode {
+            DetectionMode::Height => finger.tipPosition.y < self.press_height,
+            DetectionMode::Flexion => finger_flexion(finger) > self.press_angle,
+        }
+    }
+
+    fn is_lifted(&self, finger: &LeapRustFinger) -> bool {
+        match self.mode {
+            DetectionMode::Height => finger.tipPosition.y > self.release_height,
+            DetectionMode::Flexion => finger_flexion(finger) < self.release_angle,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FingerTrigger {
+    pub settings: TriggerSettings,
+    pressed: bool,
+    pressed_at: i32,
+    velocity: f32,
+    // Leap's id for the finger that pressed, so a different finger the
+    // tracker swaps in can't keep the note held
+    finger_id: i32,
+}
+
+impl FingerTrigger {
+    pub fn new(settings: TriggerSettings) -> FingerTrigger {
+        FingerTrigger {
+            settings: settings,
+            pressed: false,
+            pressed_at: 0,
+            velocity: 0.0,
+            finger_id: -1,
+        }
+    }
+
+    // Follows a finger, or None when it isn't tracked, and returns the
+    // velocity of the press while it is held down
+    pub fn update(&mut self, finger: Option<&LeapRustFinger>, timestamp: i32) -> Option<f32> {
+        if !self.pressed {
+            if let Some(finger) = finger {
+                let speed = -finger.tipVelocity.y;
+                if self.settings.is_pressed(finger) && speed >= self.settings.min_speed {
+                    self.pressed = true;
+                    self.pressed_at = timestamp;
+                    self.velocity = press_velocity(finger);
+                    self.finger_id = finger.id;
+                }
+            }
+        } else {
+            let held = timestamp.wrapping_sub(self.pressed_at);
+            let lifted = finger.map_or(true, |x| x.id != self.finger_id || self.settings.is_lifted(x));
+            if lifted && held >= self.settings.min_hold {
+                self.pressed = false;
+            }
+        }
+        if self.pressed { Some(self.velocity) } else { None }
+    }
+}
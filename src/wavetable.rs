@@ -0,0 +1,208 @@
+use std::f32::consts::PI;
+
+use crate::lrcpal::try_decode_file;
+use crate::preset::Preset;
+
+pub const TABLE_SIZE: usize = 2048;
+// One mip level per octave, from all harmonics down to the fundamental only
+const LEVELS: usize = 11;
+
+// A set of single-cycle frames, each stored at several band limits so high
+// notes can read a copy without harmonics above nyquist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wavetable {
+    frames: Vec<Vec<Vec<f32>>>,
+}
+
+impl Wavetable {
+    // Empty cycles are skipped, and there has to be at least one left
+    pub fn from_cycles(cycles: &[Vec<f32>]) -> Result<Wavetable, String> {
+        let frames = cycles.iter()
+            .filter(|cycle| cycle.len() > 0)
+            .map(|cycle| build_levels(&resample_cycle(cycle)))
+            .collect::<Vec<_>>();
+        if frames.is_empty() {
+            return Err("wavetable needs at least one cycle".to_string());
+        }
+        Ok(Wavetable { frames })
+    }
+
+    // Splits a file into consecutive TABLE_SIZE sample cycles, the layout
+    // most wavetable editors export.
+    pub fn from_file(path: &str) -> Result<Wavetable, String> {
+        let (data, _) = try_decode_file(path)?;
+        let cycles: Vec<Vec<f32>> = if data.len() < TABLE_SIZE {
+            vec![data]
+        } else {
+            data.chunks_exact(TABLE_SIZE).map(|x| x.to_vec()).collect()
+        };
+        Wavetable::from_cycles(&cycles).map_err(|err| format!("{} in {}", err, path))
+    }
+
+    // Builds a single cycle from drawn (phase, value) breakpoints, with phase
+    // running from 0 to 1.
+    pub fn from_points(points: &[(f32, f32)]) -> Result<Wavetable, String> {
+        let mut points = points.to_vec();
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let cycle = (0..TABLE_SIZE)
+            .map(|i| interpolate_points(&points, i as f32 / TABLE_SIZE as f32))
+            .collect::<Vec<f32>>();
+        Wavetable::from_cycles(&[cycle])
+    }
+
+    // A file from wavetable.path, or a cycle drawn as "phase:value" pairs in
+    // wavetable.points, falling back to the basic shapes when neither is set
+    pub fn from_preset(preset: &Preset) -> Result<Wavetable, String> {
+        if let Some(path) = preset.get::<String>("wavetable.path") {
+            return Wavetable::from_file(&path);
+        }
+        let points = match preset.get::<String>("wavetable.points") {
+            Some(points) => points,
+            None => return Ok(Wavetable::basic_shapes()),
+        };
+        let points: Vec<(f32, f32)> = points
+            .split_whitespace()
+            .filter_map(|x| x.split_once(':'))
+            .filter_map(|(phase, value)| Some((phase.parse().ok()?, value.parse().ok()?)))
+            .collect();
+        if points.is_empty() {
+            return Err("no phase:value pairs in wavetable.points".to_string());
+        }
+        Wavetable::from_points(&points)
+    }
+
+    // Sine, triangle, saw and square in that order
+    pub fn basic_shapes() -> Wavetable {
+        let shape = |f: &dyn Fn(f32) -> f32| {
+            (0..TABLE_SIZE).map(|i| f(i as f32 / TABLE_SIZE as f32)).collect::<Vec<f32>>()
+        };
+        Wavetable::from_cycles(&[
+            shape(&|x| (2.0 * PI * x).sin()),
+            shape(&|x| 1.0 - 4.0 * (x - 0.5).abs()),
+            shape(&|x| 2.0 * x - 1.0),
+            shape(&|x| if x < 0.5 { 1.0 } else { -1.0 }),
+        ]).unwrap()
+    }
+
+    fn level_for(freq: f32, sample_rate: u32) -> usize {
+        let allowed_harmonics = (sample_rate as f32 / 2.0) / freq.max(1.0);
+        let needed = ((TABLE_SIZE / 2) as f32 / allowed_harmonics).log2().ceil();
+        (needed.max(0.0) as usize).min(LEVELS - 1)
+    }
+
+    fn read(&self, frame: usize, level: usize, phase: f32) -> f32 {
+        let table = &self.frames[frame][level];
+        let position = phase * TABLE_SIZE as f32;
+        let first_index = position as usize % TABLE_SIZE;
+        let second_index = (first_index + 1) % TABLE_SIZE;
+        let second_weight = position - position.floor();
+        table[first_index] * (1.0 - second_weight) + table[second_index] * second_weight
+    }
+
+    // morph runs from 0 (first frame) to 1 (last frame)
+    pub fn sample(&self, phase: f32, morph: f32, freq: f32, sample_rate: u32) -> f32 {
+        let level = Wavetable::level_for(freq, sample_rate);
+        let frame_position = morph.max(0.0).min(1.0) * (self.frames.len() - 1) as f32;
+        let first_frame = frame_position as usize;
+        let second_frame = (first_frame + 1).min(self.frames.len() - 1);
+        let second_weight = frame_position - first_frame as f32;
+        self.read(first_frame, level, phase) * (1.0 - second_weight)
+            + self.read(second_frame, level, phase) * second_weight
+    }
+}
+
+fn interpolate_points(points: &[(f32, f32)], phase: f32) -> f32 {
+    match points.iter().position(|point| point.0 > phase) {
+        None => points.last().map_or(0.0, |x| x.1),
+        Some(0) => points[0].1,
+        Some(index) => {
+            let (x0, y0) = points[index - 1];
+            let (x1, y1) = points[index];
+            y0 + (y1 - y0) * (phase - x0) / (x1 - x0)
+        }
+    }
+}
+
+fn resample_cycle(cycle: &[f32]) -> Vec<f32> {
+    if cycle.len() == TABLE_SIZE {
+        return cycle.to_vec();
+    }
+    (0..TABLE_SIZE)
+        .map(|i| {
+            let position = i as f32 * cycle.len() as f32 / TABLE_SIZE as f32;
+            let first_index = position as usize % cycle.len();
+            let second_index = (first_index + 1) % cycle.len();
+            let second_weight = position - position.floor();
+            cycle[first_index] * (1.0 - second_weight) + cycle[second_index] * second_weight
+        })
+        .collect()
+}
+
+// Band limits the cycle once per octave by zeroing the harmonics above each
+// level's limit, then normalizes every level by the full band peak.
+fn build_levels(cycle: &[f32]) -> Vec<Vec<f32>> {
+    let mut re = cycle.to_vec();
+    let mut im = vec![0f32; TABLE_SIZE];
+    fft(&mut re, &mut im, false);
+    let mut levels = Vec::with_capacity(LEVELS);
+    for level in 0..LEVELS {
+        let max_harmonic = (TABLE_SIZE / 2) >> level;
+        let mut level_re = re.clone();
+        let mut level_im = im.clone();
+        // drop DC along with everything above the limit
+        for bin in 0..TABLE_SIZE {
+            let harmonic = bin.min(TABLE_SIZE - bin);
+            if harmonic == 0 || harmonic > max_harmonic {
+                level_re[bin] = 0.0;
+                level_im[bin] = 0.0;
+            }
+        }
+        fft(&mut level_re, &mut level_im, true);
+        levels.push(level_re.iter().map(|x| x / TABLE_SIZE as f32).collect::<Vec<f32>>());
+    }
+    let peak = levels[0].iter().cloned().map(f32::abs).fold(0f32, f32::max);
+    if peak > 0.0 {
+        for level in &mut levels {
+            for value in level.iter_mut() {
+                *value /= peak;
+            }
+        }
+    }
+    levels
+}
+
+// In place radix-2 FFT; the inverse transform is left unscaled
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j ^= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::leaprust::LeapRustHand;
+use crate::preset::Preset;
+
+// Angles here are in degrees so the zones read easily in the preset
+#[derive(Debug, Clone, Copy, Display, PartialEq)]
+pub enum WristAxis {
+    // turning the palm to face left or right, 0 with the palm flat
+    Roll,
+    // tipping the fingers up or down, 0 with the hand level
+    Pitch,
+}
+
+impl FromStr for WristAxis {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<WristAxis, String> {
+        match s {
+            "Roll" => Ok(WristAxis::Roll),
+            "Pitch" => Ok(WristAxis::Pitch),
+            _ => Err(format!("unknown wrist axis {}", s)),
+        }
+    }
+}
+
+impl WristAxis {
+    pub fn angle(&self, hand: &LeapRustHand) -> f32 {
+        let radians = match self {
+            WristAxis::Roll => hand.palmNormal.x.atan2(-hand.palmNormal.y),
+            WristAxis::Pitch => hand.direction.y.atan2(-hand.direction.z),
+        };
+        radians.to_degrees()
+    }
+}
+
+// Holding the wrist between low and high picks the map
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WristZone {
+    pub low: f32,
+    pub high: f32,
+    pub map: i32,
+}
+
+impl WristZone {
+    fn contains(&self, angle: f32, margin: f32) -> bool {
+        angle >= self.low - margin && angle < self.high + margin
+    }
+}
+
+// Written to presets as "low high map"
+impl fmt::Display for WristZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.low, self.high, self.map)
+    }
+}
+
+impl FromStr for WristZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<WristZone, String> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err(format!("wrist zone needs a low angle, high angle and map: {}", s));
+        }
+        let number = |x: &str| x.parse::<f32>().map_err(|_| format!("bad number {} in {}", x, s));
+        let map = parts[2].parse::<i32>().map_err(|_| format!("bad map {} in {}", parts[2], s))?;
+        Ok(WristZone { low: number(parts[0])?, high: number(parts[1])?, map: map })
+    }
+}
+
+// Picks a map from the wrist angle. The active zone stretches by the
+// hysteresis on both sides, so a wrist held on a border doesn't flicker
+// between two maps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WristSwitch {
+    pub axis: WristAxis,
+    pub zones: Vec<WristZone>,
+    pub hysteresis: f32,
+    pub active: Option<usize>,
+    // last reading, kept for drawing
+    pub angle: Option<f32>,
+}
+
+impl WristSwitch {
+    // Five zones from palm turned left to palm turned right, in the same
+    // order the left hand's little finger to thumb used to pick them
+    pub fn new() -> WristSwitch {
+        let zones = (0..5)
+            .map(|x| WristZone { low: -90.0 + 36.0 * x as f32, high: -54.0 + 36.0 * x as f32, map: x })
+            .collect();
+        WristSwitch {
+            axis: WristAxis::Roll,
+            zones: zones,
+            hysteresis: 6.0,
+            active: None,
+            angle: None,
+        }
+    }
+
+    // The map to play, or None while the wrist is outside every zone
+    pub fn update(&mut self, hand: &LeapRustHand) -> Option<i32> {
+        let angle = self.axis.angle(hand);
+        self.angle = Some(angle);
+        let still_active = self.active
+            .and_then(|x| self.zones.get(x))
+            .map_or(false, |x| x.contains(angle, self.hysteresis));
+        if !still_active {
+            self.active = self.zones.iter().position(|x| x.contains(angle, 0.0));
+        }
+        self.active.map(|x| self.zones[x].map)
+    }
+
+    // Lowest and highest angle covered by any zone
+    pub fn range(&self) -> (f32, f32) {
+        self.zones.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |range, x| (range.0.min(x.low), range.1.max(x.high)))
+    }
+
+    // Zones are stored as wrist.zone.0, wrist.zone.1, ... and read until the
+    // first gap
+    pub fn from_preset(preset: &Preset) -> WristSwitch {
+        let mut switch = WristSwitch::new();
+        switch.axis = preset.get("wrist.axis").unwrap_or(switch.axis);
+        switch.hysteresis = preset.get("wrist.hysteresis").unwrap_or(switch.hysteresis);
+        let mut zones = Vec::new();
+        while let Some(line) = preset.get::<String>(&format!("wrist.zone.{}", zones.len())) {
+            match line.parse() {
+                Ok(zone) => zones.push(zone),
+                Err(err) => {
+                    println!("Ignoring {}", err);
+                    break;
+                }
+            }
+        }
+        if !zones.is_empty() {
+            switch.zones = zones;
+        }
+        switch
+    }
+
+    pub fn store_preset(&self, preset: &mut Preset) {
+        preset.set("wrist.axis", self.axis);
+        preset.set("wrist.hysteresis", self.hysteresis);
+        for (index, zone) in self.zones.iter().enumerate() {
+            preset.set(&format!("wrist.zone.{}", index), zone);
+        }
+    }
+}